@@ -0,0 +1,767 @@
+//! Querying WireGuard devices over generic netlink.
+//!
+//! [`WgClient`] is the entry point: it owns a generic netlink socket and the
+//! resolved wireguard family id. The free functions underneath it are the
+//! building blocks the `wg-test` binary uses directly.
+//!
+//! Sockets are non-blocking and wrapped in [`Async`], so requests can be
+//! awaited without blocking the executor. A socket carries one request at a
+//! time; query interfaces concurrently through a socket each.
+
+use std::{
+    collections::HashMap,
+    ffi::{CString, OsStr},
+    net::IpAddr,
+    os::unix::ffi::OsStrExt as _,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use async_io::Async;
+use libc::{MSG_PEEK, MSG_TRUNC, NLM_F_ACK, NLM_F_DUMP, NLM_F_REQUEST};
+use netlink_packet_core::{
+    NetlinkDeserializable, NetlinkMessage, NetlinkPayload, NetlinkSerializable,
+};
+use netlink_packet_generic::{
+    ctrl::{nlas::GenlCtrlAttrs, GenlCtrl, GenlCtrlCmd},
+    GenlFamily, GenlMessage,
+};
+use netlink_packet_wireguard::{
+    nlas::{WgAllowedIp, WgAllowedIpAttrs, WgDeviceAttrs, WgPeerAttrs},
+    Wireguard, WireguardCmd,
+};
+use netlink_sys::{protocols::NETLINK_GENERIC, Socket, SocketAddr};
+
+/// A generic netlink socket with the wireguard family resolved on it.
+pub struct WgClient {
+    socket: Async<Socket>,
+    family: WgFamily,
+}
+
+/// A wireguard device as reported by GetDevice.
+///
+/// Keys and fields the kernel leaves out when unset are `None`; the
+/// attributes nothing here covers are kept in `other_attrs`.
+#[derive(Debug, Default)]
+pub struct WgDevice {
+    pub ifindex: Option<u32>,
+    pub name: Option<String>,
+    pub private_key: Option<[u8; 32]>,
+    pub public_key: Option<[u8; 32]>,
+    pub listen_port: u16,
+    pub fwmark: u32,
+    pub flags: Option<u32>,
+    pub peers: Vec<WgPeer>,
+    pub other_attrs: Vec<WgDeviceAttrs>,
+}
+
+/// A peer of a [`WgDevice`].
+///
+/// An all-zero preshared key and a zero handshake time are how the kernel
+/// says there is none, so both become `None`, as does an unspecified
+/// endpoint. The kernel reports `protocol_version` on every peer; it is 1
+/// for all current kernels.
+#[derive(Debug, Default)]
+pub struct WgPeer {
+    pub public_key: [u8; 32],
+    pub preshared_key: Option<[u8; 32]>,
+    pub endpoint: Option<std::net::SocketAddr>,
+    pub persistent_keepalive: u16,
+    pub last_handshake: Option<SystemTime>,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    pub allowed_ips: Vec<(IpAddr, u8)>,
+    pub protocol_version: Option<u32>,
+    pub other_attrs: Vec<WgPeerAttrs>,
+}
+
+impl WgDevice {
+    /// The peer with `public_key`, if the device has one.
+    ///
+    /// ```
+    /// use wg_test::{WgDevice, WgPeer};
+    ///
+    /// let device = WgDevice {
+    ///     peers: vec![WgPeer { public_key: [1; 32], ..Default::default() }],
+    ///     ..Default::default()
+    /// };
+    /// assert!(device.peer(&[1; 32]).is_some());
+    /// assert!(device.peer(&[2; 32]).is_none());
+    /// ```
+    pub fn peer(&self, public_key: &[u8; 32]) -> Option<&WgPeer> {
+        self.peers
+            .iter()
+            .find(|peer| peer.public_key == *public_key)
+    }
+
+    /// The peer whose public key is `public_key` in base64, as `wg` prints
+    /// it. Keys that aren't valid base64 of 32 bytes match no peer.
+    ///
+    /// Base64 is case-sensitive, so this compares the decoded bytes rather
+    /// than the text.
+    ///
+    /// ```
+    /// use wg_test::{WgDevice, WgPeer};
+    ///
+    /// let device = WgDevice {
+    ///     peers: vec![WgPeer { public_key: [1; 32], ..Default::default() }],
+    ///     ..Default::default()
+    /// };
+    /// let key = "AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE=";
+    /// assert_eq!(device.peer_by_base64(key).unwrap().public_key, [1; 32]);
+    /// assert!(device.peer_by_base64(&key.to_lowercase()).is_none());
+    /// assert!(device.peer_by_base64("not a key").is_none());
+    /// ```
+    pub fn peer_by_base64(&self, public_key: &str) -> Option<&WgPeer> {
+        self.peer(&wg_decode_key(public_key)?)
+    }
+
+    /// Builds a device from all the messages of a GetDevice reply.
+    ///
+    /// The kernel splits large devices across several messages: the device
+    /// attributes are in the first one and peers may continue over the rest,
+    /// so see [`WgDevice::merge`].
+    pub fn from_messages(
+        messages: impl IntoIterator<Item = GenlMessage<Wireguard>>,
+    ) -> std::io::Result<Self> {
+        let mut messages = messages.into_iter();
+        let mut device = match messages.next() {
+            Some(message) => Self::try_from(message)?,
+            None => Self::default(),
+        };
+        for message in messages {
+            device.merge(message.try_into()?);
+        }
+        Ok(device)
+    }
+
+    /// Adds the peers of `other`, a later message of the same reply. Its
+    /// device attributes, if any, are ignored.
+    ///
+    /// Peers with many allowed ips continue in the next message, repeating
+    /// the public key; their allowed ips are appended to the existing peer.
+    /// The kernel doesn't allow two peers with the same key, so entries that
+    /// disagree on the endpoint point at a broken reply and are warned about.
+    pub fn merge(&mut self, other: WgDevice) {
+        let mut index: HashMap<_, _> = self
+            .peers
+            .iter()
+            .enumerate()
+            .map(|(i, peer)| (peer.public_key, i))
+            .collect();
+
+        for peer in other.peers {
+            match index.get(&peer.public_key) {
+                Some(&i) => self.peers[i].join(peer),
+                None => {
+                    index.insert(peer.public_key, self.peers.len());
+                    self.peers.push(peer);
+                }
+            }
+        }
+    }
+}
+
+impl WgPeer {
+    /// Adds `other`, a later entry for the same peer: its allowed ips are
+    /// appended, and its endpoint, if any, wins.
+    fn join(&mut self, other: WgPeer) {
+        if let (Some(old), Some(new)) = (self.endpoint, other.endpoint) {
+            if old != new {
+                log::warn!(
+                    "duplicate peer {} with conflicting endpoints {old} and {new}",
+                    wg_public_key(&other.public_key)
+                );
+            }
+        }
+        self.endpoint = other.endpoint.or(self.endpoint);
+        self.allowed_ips.extend(other.allowed_ips);
+    }
+}
+
+/// Puts the peers of a GetDevice dump back together as the dump streams in.
+///
+/// A peer that doesn't fit in one message continues at the start of the
+/// next, so only the last peer seen can still grow. It is held back until a
+/// different peer or the end of the dump shows it is complete; nothing else
+/// is kept.
+#[derive(Default)]
+pub struct WgPeerStream {
+    pending: Option<WgPeer>,
+}
+
+impl WgPeerStream {
+    /// Adds the next peer of the dump, returning the previous one once it is
+    /// complete.
+    pub fn push(&mut self, peer: WgPeer) -> Option<WgPeer> {
+        match &mut self.pending {
+            Some(pending) if pending.public_key == peer.public_key => {
+                pending.join(peer);
+                None
+            }
+            pending => pending.replace(peer),
+        }
+    }
+
+    /// Ends the dump, returning the peer still held back.
+    pub fn finish(&mut self) -> Option<WgPeer> {
+        self.pending.take()
+    }
+}
+
+impl TryFrom<GenlMessage<Wireguard>> for WgDevice {
+    type Error = std::io::Error;
+
+    /// Converts a single message; peers without a public key are skipped.
+    fn try_from(message: GenlMessage<Wireguard>) -> std::io::Result<Self> {
+        if message.payload.cmd != WireguardCmd::GetDevice {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("expected a GetDevice reply, got {:?}", message.payload.cmd),
+            ));
+        }
+
+        let mut device = Self::default();
+        for nla in message.payload.nlas {
+            match nla {
+                WgDeviceAttrs::IfIndex(index) => device.ifindex = Some(index),
+                WgDeviceAttrs::IfName(name) => device.name = Some(name),
+                WgDeviceAttrs::PrivateKey(key) => device.private_key = Some(key),
+                WgDeviceAttrs::PublicKey(key) => device.public_key = Some(key),
+                WgDeviceAttrs::ListenPort(port) => device.listen_port = port,
+                WgDeviceAttrs::Fwmark(fwmark) => device.fwmark = fwmark,
+                WgDeviceAttrs::Flags(flags) => device.flags = Some(flags),
+                WgDeviceAttrs::Peers(peers) => {
+                    device.peers.extend(peers.into_iter().filter_map(wg_peer))
+                }
+                nla => device.other_attrs.push(nla),
+            }
+        }
+        Ok(device)
+    }
+}
+
+fn wg_peer(nlas: netlink_packet_wireguard::nlas::WgPeer) -> Option<WgPeer> {
+    let mut public_key = None;
+    let mut peer = WgPeer::default();
+
+    for nla in nlas.0 {
+        match nla {
+            WgPeerAttrs::PublicKey(key) => public_key = Some(key),
+            WgPeerAttrs::PresharedKey(key) => peer.preshared_key = (key != [0; 32]).then_some(key),
+            WgPeerAttrs::Endpoint(endpoint) => peer.endpoint = wg_endpoint(endpoint),
+            WgPeerAttrs::PersistentKeepalive(secs) => peer.persistent_keepalive = secs,
+            WgPeerAttrs::LastHandshake(ts) => {
+                peer.last_handshake = (ts != UNIX_EPOCH).then_some(ts)
+            }
+            WgPeerAttrs::RxBytes(bytes) => peer.rx_bytes = bytes,
+            WgPeerAttrs::TxBytes(bytes) => peer.tx_bytes = bytes,
+            WgPeerAttrs::AllowedIps(ips) => peer.allowed_ips.extend(wg_allowed_ips(&ips)),
+            WgPeerAttrs::ProtocolVersion(version) => peer.protocol_version = Some(version),
+            nla => peer.other_attrs.push(nla),
+        }
+    }
+
+    let Some(public_key) = public_key else {
+        log::debug!("skipping peer without a public key: {peer:?}");
+        return None;
+    };
+    Some(WgPeer { public_key, ..peer })
+}
+
+pub fn wg_allowed_ips(ips: &[WgAllowedIp]) -> Vec<(IpAddr, u8)> {
+    ips.iter()
+        .filter_map(|ip| {
+            let mut found_ip = None;
+            let mut found_cidr = None;
+
+            for attr in ip.0.iter() {
+                match attr {
+                    WgAllowedIpAttrs::IpAddr(ip) => found_ip = Some(*ip),
+                    WgAllowedIpAttrs::Cidr(cidr) => found_cidr = Some(*cidr),
+                    _ => {}
+                }
+            }
+            let found_ip = found_ip?;
+            let found_cidr = found_cidr?;
+
+            Some((found_ip, found_cidr))
+        })
+        .collect()
+}
+
+/// Cleans up a peer endpoint as reported by the kernel.
+///
+/// A v4-mapped IPv6 endpoint (`[::ffff:1.2.3.4]:51820`), which is how some
+/// kernels report IPv4 peers on dual-stack sockets, becomes the plain IPv4
+/// one. An unspecified address means the peer has no endpoint at all.
+///
+/// The netlink API reports a single endpoint per peer and doesn't say whether
+/// it was configured or learned from the peer roaming, so neither can this.
+pub fn wg_endpoint(endpoint: std::net::SocketAddr) -> Option<std::net::SocketAddr> {
+    let endpoint = match endpoint {
+        std::net::SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(ip) => (ip, v6.port()).into(),
+            None => endpoint,
+        },
+        endpoint => endpoint,
+    };
+
+    (!endpoint.ip().is_unspecified()).then_some(endpoint)
+}
+
+pub fn wg_public_key(key: &[u8; 32]) -> String {
+    use base64::Engine as _;
+    base64::engine::general_purpose::STANDARD.encode(key)
+}
+
+/// Decodes a key in the base64 form [`wg_public_key`] prints.
+pub fn wg_decode_key(key: &str) -> Option<[u8; 32]> {
+    use base64::Engine as _;
+    let key = base64::engine::general_purpose::STANDARD.decode(key).ok()?;
+    key.try_into().ok()
+}
+
+impl WgClient {
+    /// Opens a generic netlink socket and resolves the wireguard family.
+    pub async fn new() -> std::io::Result<Self> {
+        let mut socket = Socket::new(NETLINK_GENERIC)?;
+        socket.bind(&SocketAddr::new(0, 0))?;
+        Self::with_socket(socket).await
+    }
+
+    /// Resolves the wireguard family on an already bound `socket`, which is
+    /// made non-blocking.
+    ///
+    /// Fails with [`std::io::ErrorKind::NotFound`] if the kernel has no
+    /// wireguard family.
+    pub async fn with_socket(socket: Socket) -> std::io::Result<Self> {
+        let mut socket = Async::new(socket)?;
+        let family = wg_family(&mut socket).await?.ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "no wireguard family id in reply",
+            )
+        })?;
+        Ok(Self { socket, family })
+    }
+
+    pub fn family(&self) -> &WgFamily {
+        &self.family
+    }
+
+    pub fn socket_mut(&mut self) -> &mut Async<Socket> {
+        &mut self.socket
+    }
+
+    /// Sends GetDevice for `interface` and collects the reply.
+    pub async fn get_device(&mut self, interface: impl AsRef<OsStr>) -> std::io::Result<WgDevice> {
+        wg_device(&mut self.socket, self.family.id, interface.as_ref()).await
+    }
+}
+
+pub async fn socket_send<Message>(
+    socket: &mut Async<Socket>,
+    packet: &NetlinkMessage<Message>,
+) -> std::io::Result<usize>
+where
+    Message: NetlinkSerializable + std::fmt::Debug,
+{
+    log::debug!("<- {packet:?}");
+
+    let mut buf = vec![0u8; packet.header.length as usize].into_boxed_slice();
+    assert_eq!(buf.len(), packet.buffer_len());
+
+    packet.serialize(&mut buf);
+
+    log::trace!("<- {buf:?}");
+    socket.write_with(|socket| socket.send(&buf, 0)).await
+}
+
+pub async fn socket_recv<Message>(socket: &mut Async<Socket>) -> std::io::Result<Vec<Message>>
+where
+    Message: NetlinkDeserializable + Clone + std::fmt::Debug,
+{
+    let mut messages = Vec::new();
+    socket_recv_each(socket, |message| messages.push(message)).await?;
+    Ok(messages)
+}
+
+/// Like [`socket_recv`], but hands each message to `f` as soon as its
+/// datagram is read, so a large dump is never held in memory at once.
+pub async fn socket_recv_each<Message>(
+    socket: &mut Async<Socket>,
+    mut f: impl FnMut(Message),
+) -> std::io::Result<()>
+where
+    Message: NetlinkDeserializable + Clone + std::fmt::Debug,
+{
+    let mut buf = vec![0u8; 4096];
+
+    loop {
+        // A dump datagram can be larger than any fixed buffer, so peek at its
+        // real size first and grow the buffer before taking it off the socket.
+        let (len, _) = socket
+            .read_with(|socket| {
+                let (len, _) = socket.recv_from(&mut &mut buf[..], MSG_PEEK | MSG_TRUNC)?;
+                if len > buf.len() {
+                    buf.resize(len, 0);
+                }
+                socket.recv_from(&mut &mut buf[..], 0)
+            })
+            .await?;
+        let buf = &buf[..len];
+
+        log::trace!("-> {buf:?}");
+
+        let mut offset = 0;
+        while offset < len {
+            let packet = NetlinkMessage::<Message>::deserialize(&buf[offset..])
+                .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+
+            log::debug!("-> {packet:?}");
+
+            let length = packet.header.length as usize;
+            if length == 0 || offset + length > len {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("bad netlink message length {length} at offset {offset} of {len}"),
+                ));
+            }
+
+            match packet.payload {
+                NetlinkPayload::Done(_) => return Ok(()),
+                NetlinkPayload::InnerMessage(message) => f(message),
+                NetlinkPayload::Error(err) => {
+                    return match err.code {
+                        Some(_) => Err(err.into()),
+                        None => Ok(()),
+                    };
+                }
+                _ => {}
+            }
+
+            // Messages inside a datagram start on 4-byte boundaries.
+            offset += (length + 3) & !3;
+        }
+    }
+}
+
+/// The wireguard generic-netlink family as registered in the kernel.
+pub struct WgFamily {
+    pub id: u16,
+    pub version: Option<u32>,
+}
+
+/// Resolves the wireguard generic-netlink family on `socket`.
+///
+/// Family ids are assigned per network namespace, so the lookup has to go
+/// through the same socket the device requests are sent on. Never cache the
+/// result across sockets: a socket created in (or moved to) another namespace
+/// may see a different id for the same family.
+pub async fn wg_family(socket: &mut Async<Socket>) -> std::io::Result<Option<WgFamily>> {
+    let mut message = NetlinkMessage::from(GenlMessage::from_payload(GenlCtrl {
+        cmd: GenlCtrlCmd::GetFamily,
+        nlas: vec![GenlCtrlAttrs::FamilyName(
+            Wireguard::family_name().to_owned(),
+        )],
+    }));
+    message.header.flags = (NLM_F_REQUEST | NLM_F_ACK) as _;
+    message.finalize();
+
+    socket_send(socket, &message).await?;
+
+    let messages = socket_recv::<GenlMessage<GenlCtrl>>(socket).await?;
+
+    let mut id = None;
+    let mut version = None;
+    for attr in messages.into_iter().flat_map(|msg| msg.payload.nlas) {
+        match attr {
+            GenlCtrlAttrs::FamilyId(family_id) => id = Some(family_id),
+            GenlCtrlAttrs::Version(family_version) => version = Some(family_version),
+            _ => {}
+        }
+    }
+
+    Ok(id.map(|id| WgFamily { id, version }))
+}
+
+/// Index of the network interface named `interface`.
+pub fn if_index(interface: &OsStr) -> std::io::Result<u32> {
+    let name = CString::new(interface.as_bytes())
+        .map_err(|_| std::io::Error::from_raw_os_error(libc::ENODEV))?;
+    // SAFETY: `name` is a valid NUL-terminated string.
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => Err(std::io::Error::last_os_error()),
+        index => Ok(index),
+    }
+}
+
+/// The attribute selecting `interface` in a wireguard request.
+///
+/// `IfName` can only carry UTF-8, so other names are resolved to an
+/// interface index, which the kernel accepts instead.
+pub fn wg_device_selector(interface: &OsStr) -> std::io::Result<WgDeviceAttrs> {
+    if let Some(name) = interface.to_str() {
+        return Ok(WgDeviceAttrs::IfName(name.to_owned()));
+    }
+    log::warn!(
+        "interface name {} is not valid UTF-8, looking it up by index",
+        interface.display()
+    );
+    Ok(WgDeviceAttrs::IfIndex(if_index(interface)?))
+}
+
+pub async fn wg_get_device(
+    socket: &mut Async<Socket>,
+    family_id: u16,
+    interface: &OsStr,
+) -> std::io::Result<Vec<GenlMessage<Wireguard>>> {
+    let mut messages = Vec::new();
+    wg_get_device_each(socket, family_id, interface, |message| {
+        messages.push(message)
+    })
+    .await?;
+    Ok(messages)
+}
+
+/// Like [`wg_get_device`], but with the reply parsed into a [`WgDevice`].
+pub async fn wg_device(
+    socket: &mut Async<Socket>,
+    family_id: u16,
+    interface: &OsStr,
+) -> std::io::Result<WgDevice> {
+    WgDevice::from_messages(wg_get_device(socket, family_id, interface).await?)
+}
+
+/// Sends GetDevice for `interface`, handing each reply message to `f` as it
+/// arrives.
+pub async fn wg_get_device_each(
+    socket: &mut Async<Socket>,
+    family_id: u16,
+    interface: &OsStr,
+    f: impl FnMut(GenlMessage<Wireguard>),
+) -> std::io::Result<()> {
+    let mut message = NetlinkMessage::from(GenlMessage::from_payload(Wireguard {
+        cmd: WireguardCmd::GetDevice,
+        nlas: vec![wg_device_selector(interface)?],
+    }));
+    message.header.flags = (NLM_F_REQUEST | NLM_F_ACK | NLM_F_DUMP) as _;
+    let NetlinkPayload::InnerMessage(ref mut payload) = message.payload else {
+        panic!();
+    };
+    payload.set_resolved_family_id(family_id);
+    message.finalize();
+
+    socket_send(socket, &message).await?;
+
+    socket_recv_each(socket, f).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        alloc::{GlobalAlloc, Layout, System},
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use super::*;
+
+    fn get_device(peers: Vec<Vec<WgPeerAttrs>>) -> GenlMessage<Wireguard> {
+        GenlMessage::from_payload(Wireguard {
+            cmd: WireguardCmd::GetDevice,
+            nlas: vec![WgDeviceAttrs::Peers(
+                peers
+                    .into_iter()
+                    .map(netlink_packet_wireguard::nlas::WgPeer)
+                    .collect(),
+            )],
+        })
+    }
+
+    #[test]
+    fn peer_without_public_key_is_skipped() {
+        let message = get_device(vec![
+            vec![
+                WgPeerAttrs::Endpoint("192.0.2.1:51820".parse().unwrap()),
+                WgPeerAttrs::RxBytes(1),
+            ],
+            vec![WgPeerAttrs::PublicKey([1; 32])],
+        ]);
+
+        let device = WgDevice::try_from(message).unwrap();
+        assert_eq!(device.peers.len(), 1);
+        assert_eq!(device.peers[0].public_key, [1; 32]);
+    }
+
+    #[test]
+    fn v4_mapped_endpoint_becomes_ipv4() {
+        let endpoint = wg_endpoint("[::ffff:1.2.3.4]:51820".parse().unwrap());
+        assert_eq!(endpoint, Some("1.2.3.4:51820".parse().unwrap()));
+        assert_eq!(endpoint.unwrap().to_string(), "1.2.3.4:51820");
+    }
+
+    #[test]
+    fn unspecified_endpoint_is_none() {
+        assert_eq!(wg_endpoint("0.0.0.0:0".parse().unwrap()), None);
+        assert_eq!(wg_endpoint("[::]:51820".parse().unwrap()), None);
+        assert_eq!(wg_endpoint("[::ffff:0.0.0.0]:0".parse().unwrap()), None);
+    }
+
+    #[test]
+    fn merge_conflicting_entries_for_the_same_key() {
+        let entry = |endpoint: &str, ip: &str| {
+            let peer = WgPeer {
+                public_key: [1; 32],
+                endpoint: Some(endpoint.parse().unwrap()),
+                allowed_ips: vec![(ip.parse().unwrap(), 32)],
+                ..Default::default()
+            };
+            WgDevice {
+                peers: vec![peer],
+                ..Default::default()
+            }
+        };
+
+        let mut device = entry("192.0.2.1:51820", "10.0.0.1");
+        device.merge(entry("192.0.2.2:51820", "10.0.0.2"));
+
+        assert_eq!(device.peers.len(), 1);
+        let peer = &device.peers[0];
+        assert_eq!(
+            peer.allowed_ips,
+            [
+                ("10.0.0.1".parse().unwrap(), 32),
+                ("10.0.0.2".parse().unwrap(), 32)
+            ]
+        );
+        // The later entry's endpoint wins.
+        assert_eq!(peer.endpoint, Some("192.0.2.2:51820".parse().unwrap()));
+    }
+
+    /// Counts the bytes allocated by this test binary, and the most at once.
+    struct CountingAlloc;
+
+    static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+    static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+    unsafe impl GlobalAlloc for CountingAlloc {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let ptr = System.alloc(layout);
+            if !ptr.is_null() {
+                let allocated = ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+                PEAK.fetch_max(allocated + layout.size(), Ordering::Relaxed);
+            }
+            ptr
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout);
+            ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        }
+    }
+
+    #[global_allocator]
+    static GLOBAL: CountingAlloc = CountingAlloc;
+
+    /// Writes a GetDevice dump of `peers` peers to `socket` the way the kernel
+    /// lays it out: several messages per datagram, and the last peer of each
+    /// message continued in the next one with another allowed ip.
+    fn write_dump(socket: std::os::unix::net::UnixDatagram, peers: u32) {
+        const PEERS_PER_MESSAGE: u32 = 50;
+        const MESSAGES_PER_DATAGRAM: usize = 4;
+
+        let key = |i: u32| {
+            let mut key = [0; 32];
+            key[..4].copy_from_slice(&i.to_le_bytes());
+            key
+        };
+        let allowed_ip = |i: u32, n: u8| {
+            WgPeerAttrs::AllowedIps(vec![WgAllowedIp(vec![
+                WgAllowedIpAttrs::IpAddr(IpAddr::from([10, (i >> 8) as u8, i as u8, n])),
+                WgAllowedIpAttrs::Cidr(32),
+            ])])
+        };
+        let serialize = |datagram: &mut Vec<u8>, mut message: NetlinkMessage<_>| {
+            message.header.flags = libc::NLM_F_MULTI as _;
+            message.finalize();
+            let start = datagram.len();
+            datagram.resize(start + message.buffer_len(), 0);
+            message.serialize(&mut datagram[start..]);
+        };
+
+        let mut datagram = Vec::new();
+        for (n, first) in (0..peers).step_by(PEERS_PER_MESSAGE as usize).enumerate() {
+            let last = (first + PEERS_PER_MESSAGE).min(peers);
+            let mut entries = Vec::new();
+            if first != 0 {
+                entries.push(vec![
+                    WgPeerAttrs::PublicKey(key(first - 1)),
+                    allowed_ip(first - 1, 2),
+                ]);
+            }
+            for i in first..last {
+                entries.push(vec![
+                    WgPeerAttrs::PublicKey(key(i)),
+                    WgPeerAttrs::RxBytes(i.into()),
+                    allowed_ip(i, 1),
+                ]);
+            }
+            serialize(&mut datagram, NetlinkMessage::from(get_device(entries)));
+
+            if (n + 1).is_multiple_of(MESSAGES_PER_DATAGRAM) {
+                socket.send(&datagram).unwrap();
+                datagram.clear();
+            }
+        }
+        let done = NetlinkPayload::<GenlMessage<Wireguard>>::Done(Default::default());
+        serialize(&mut datagram, NetlinkMessage::new(Default::default(), done));
+        socket.send(&datagram).unwrap();
+    }
+
+    #[test]
+    fn streaming_a_large_dump_holds_one_datagram() {
+        use std::os::fd::{FromRawFd as _, IntoRawFd as _};
+
+        const PEERS: u32 = 50_000;
+
+        let (reader, writer) = std::os::unix::net::UnixDatagram::pair().unwrap();
+        // SAFETY: `reader` is an open socket whose fd ownership is handed over.
+        let socket = unsafe { Socket::from_raw_fd(reader.into_raw_fd()) };
+        let mut socket = Async::new(socket).unwrap();
+
+        let baseline = ALLOCATED.load(Ordering::Relaxed);
+        PEAK.store(baseline, Ordering::Relaxed);
+
+        let writer = std::thread::spawn(move || write_dump(writer, PEERS));
+
+        let mut stream = WgPeerStream::default();
+        let (mut peers, mut allowed_ips) = (0u32, 0);
+        let mut check = |peer: WgPeer| {
+            assert_eq!(peer.public_key[..4], peers.to_le_bytes());
+            peers += 1;
+            allowed_ips += peer.allowed_ips.len();
+        };
+        async_io::block_on(socket_recv_each(
+            &mut socket,
+            |msg: GenlMessage<Wireguard>| {
+                let device = WgDevice::try_from(msg).unwrap();
+                device
+                    .peers
+                    .into_iter()
+                    .filter_map(|peer| stream.push(peer))
+                    .for_each(&mut check);
+            },
+        ))
+        .unwrap();
+        stream.finish().into_iter().for_each(&mut check);
+        writer.join().unwrap();
+
+        assert_eq!(peers, PEERS);
+        // The last peer of every message but the final one is continued.
+        assert_eq!(allowed_ips as u32, PEERS + PEERS.div_ceil(50) - 1);
+
+        // Holding all the peers would take several megabytes.
+        let peak = PEAK.load(Ordering::Relaxed) - baseline;
+        assert!(peak < 1 << 20, "peak allocation was {peak} bytes");
+    }
+}
@@ -1,201 +1,5245 @@
-use std::time::UNIX_EPOCH;
-
-use libc::{NLM_F_ACK, NLM_F_DUMP, NLM_F_REQUEST};
-use netlink_packet_core::{
-    NetlinkDeserializable, NetlinkMessage, NetlinkPayload, NetlinkSerializable,
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    ffi::{OsStr, OsString},
+    io::{BufRead as _, IsTerminal as _},
+    net::IpAddr,
+    os::{
+        fd::{AsRawFd, FromRawFd, RawFd},
+        unix::ffi::OsStringExt as _,
+    },
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
-use netlink_packet_generic::{
-    ctrl::{nlas::GenlCtrlAttrs, GenlCtrl, GenlCtrlCmd},
-    GenlFamily, GenlMessage,
+
+use async_io::Async;
+use clap::builder::styling::{AnsiColor, Effects, Styles};
+use libc::{NLM_F_ACK, NLM_F_CREATE, NLM_F_DUMP, NLM_F_EXCL, NLM_F_REQUEST};
+use netlink_packet_core::{NetlinkMessage, NetlinkPayload};
+use netlink_packet_generic::GenlMessage;
+use netlink_packet_route::{
+    link::{InfoKind, LinkAttribute, LinkFlags, LinkInfo, LinkMessage, State},
+    RouteNetlinkMessage,
 };
+use netlink_packet_utils::Emitable as _;
 use netlink_packet_wireguard::{
-    nlas::{WgAllowedIp, WgAllowedIpAttrs, WgDeviceAttrs, WgPeerAttrs},
+    constants::{
+        AF_INET, AF_INET6, WGDEVICE_F_REPLACE_PEERS, WGPEER_F_REMOVE_ME,
+        WGPEER_F_REPLACE_ALLOWEDIPS, WGPEER_F_UPDATE_ONLY,
+    },
+    nlas::{self, WgAllowedIp, WgAllowedIpAttrs, WgDeviceAttrs, WgPeerAttrs},
     Wireguard, WireguardCmd,
 };
-use netlink_sys::{protocols::NETLINK_GENERIC, Socket, SocketAddr};
+use netlink_sys::{
+    protocols::{NETLINK_GENERIC, NETLINK_ROUTE},
+    Socket, SocketAddr,
+};
+use wg_test::{
+    if_index, socket_recv, socket_send, wg_decode_key, wg_device, wg_device_selector, wg_endpoint,
+    wg_family, wg_get_device, wg_get_device_each, wg_public_key, WgClient, WgDevice, WgPeer,
+    WgPeerStream,
+};
 
-fn socket_send<Message>(
-    socket: &mut Socket,
-    packet: &NetlinkMessage<Message>,
-) -> std::io::Result<usize>
-where
-    Message: NetlinkSerializable + std::fmt::Debug,
-{
-    log::debug!("<- {packet:?}");
+/// The allowed ip attribute for `ip`/`prefix`, as SetDevice takes it.
+fn wg_allowed_ip((ip, prefix): (IpAddr, u8)) -> WgAllowedIp {
+    let family = match ip {
+        IpAddr::V4(_) => AF_INET,
+        IpAddr::V6(_) => AF_INET6,
+    };
+    WgAllowedIp(vec![
+        WgAllowedIpAttrs::Family(family),
+        WgAllowedIpAttrs::IpAddr(ip),
+        WgAllowedIpAttrs::Cidr(prefix),
+    ])
+}
+
+fn format_allowed_ips(ips: &[(IpAddr, u8)]) -> String {
+    ips.iter()
+        .map(|(ip, cidr)| format!("{ip}/{cidr}"))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
 
-    let mut buf = vec![0u8; packet.header.length as usize].into_boxed_slice();
-    assert_eq!(buf.len(), packet.buffer_len());
+const DEFAULT4: (IpAddr, u8) = (IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED), 0);
+const DEFAULT6: (IpAddr, u8) = (IpAddr::V6(std::net::Ipv6Addr::UNSPECIFIED), 0);
 
-    packet.serialize(&mut buf);
+/// An `--allowed-ips` entry.
+#[derive(Clone, Copy)]
+enum AllowedIpArg {
+    Cidr((IpAddr, u8)),
+    /// The routes a default route shorthand stands for
+    Default(&'static [(IpAddr, u8)]),
+}
 
-    log::trace!("<- {buf:?}");
-    socket.send(&buf, 0)
+/// Parses a CIDR, or "default", "default4" or "default6".
+fn parse_allowed_ip(arg: &str) -> Result<AllowedIpArg, String> {
+    Ok(match arg {
+        "default" => AllowedIpArg::Default(&[DEFAULT4, DEFAULT6]),
+        "default4" => AllowedIpArg::Default(&[DEFAULT4]),
+        "default6" => AllowedIpArg::Default(&[DEFAULT6]),
+        cidr => AllowedIpArg::Cidr(parse_cidr(cidr)?),
+    })
 }
 
-fn socket_recv<Message>(socket: &mut Socket) -> std::io::Result<Vec<Message>>
-where
-    Message: NetlinkDeserializable + Clone + std::fmt::Debug,
-{
-    let mut buf = vec![0u8; 4096];
-    let mut offset = 0;
-    let mut messages = Vec::new();
+/// Expands the default route shorthands, which can't be mixed with CIDRs.
+fn expand_allowed_ips(args: &[AllowedIpArg]) -> Result<Vec<(IpAddr, u8)>, String> {
+    let shorthands = args
+        .iter()
+        .filter(|arg| matches!(arg, AllowedIpArg::Default(_)))
+        .count();
+    if shorthands != 0 && shorthands != args.len() {
+        return Err("default route shorthands can't be mixed with other CIDRs".to_owned());
+    }
 
-    loop {
-        let (len, _) = socket.recv_from(&mut &mut buf[..], 0)?;
-        let buf = &buf[..len];
+    let mut ips = Vec::new();
+    for arg in args {
+        match *arg {
+            AllowedIpArg::Cidr(ip) => ips.push(ip),
+            AllowedIpArg::Default(routes) => {
+                for &route in routes {
+                    if !ips.contains(&route) {
+                        ips.push(route);
+                    }
+                }
+            }
+        }
+    }
+    Ok(ips)
+}
+
+/// `ts` as an RFC 3339 timestamp in UTC, to the second.
+fn format_rfc3339(ts: SystemTime) -> String {
+    let secs = ts.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let (days, secs) = (secs / 86400, secs % 86400);
+
+    // The civil date of a day since 1970-01-01, with years that start in
+    // March so the leap day comes last, as in Howard Hinnant's
+    // civil_from_days.
+    let days = days + 719_468;
+    let (era, day_of_era) = (days / 146_097, days % 146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let march_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * march_month + 2) / 5 + 1;
+    let month = if march_month < 10 {
+        march_month + 3
+    } else {
+        march_month - 9
+    };
+    let year = era * 400 + year_of_era + u64::from(month <= 2);
 
-        log::trace!("-> {buf:?}");
+    let (hour, minute, second) = (secs / 3600, secs / 60 % 60, secs % 60);
+    format!("{year:04}-{month:02}-{day:02}T{hour:02}:{minute:02}:{second:02}Z")
+}
 
-        loop {
-            let buf = &buf[offset..];
+/// Parses `address/prefix`, taking a bare address as a host route.
+fn parse_cidr(cidr: &str) -> Result<(IpAddr, u8), String> {
+    let (ip, prefix) = match cidr.split_once('/') {
+        Some((ip, prefix)) => (ip, Some(prefix)),
+        None => (cidr, None),
+    };
 
-            let packet = NetlinkMessage::<Message>::deserialize(buf).unwrap();
+    let ip: IpAddr = ip.parse().map_err(|err| format!("{ip}: {err}"))?;
+    let max = if ip.is_ipv4() { 32 } else { 128 };
 
-            log::debug!("-> {packet:?}");
+    let prefix = match prefix {
+        Some(prefix) => prefix
+            .parse()
+            .ok()
+            .filter(|prefix| *prefix <= max)
+            .ok_or_else(|| format!("{prefix}: invalid prefix length"))?,
+        None => max,
+    };
 
-            match packet.payload {
-                NetlinkPayload::Done(_) => return Ok(messages),
-                NetlinkPayload::InnerMessage(message) => {
-                    messages.push(message.clone());
-                }
-                NetlinkPayload::Error(err) => {
-                    return match err.code {
-                        Some(_) => Err(err.into()),
-                        None => Ok(messages),
+    Ok((ip, prefix))
+}
+
+/// Parses a duration such as "30s", "5m", "2h" or "1h30m". A bare number
+/// is taken as seconds.
+fn parse_duration(duration: &str) -> Result<Duration, String> {
+    let invalid = || format!("{duration}: invalid duration");
+
+    if let Ok(secs) = duration.parse() {
+        return Ok(Duration::from_secs(secs));
+    }
+    if duration.is_empty() {
+        return Err(invalid());
+    }
+
+    let mut secs = 0u64;
+    let mut rest = duration;
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .filter(|digits| *digits > 0)
+            .ok_or_else(invalid)?;
+        let unit = match rest.as_bytes()[digits] {
+            b'h' => 60 * 60,
+            b'm' => 60,
+            b's' => 1,
+            _ => return Err(invalid()),
+        };
+        secs = rest[..digits]
+            .parse::<u64>()
+            .ok()
+            .and_then(|n| n.checked_mul(unit))
+            .and_then(|n| n.checked_add(secs))
+            .ok_or_else(invalid)?;
+        rest = &rest[digits + 1..];
+    }
+
+    Ok(Duration::from_secs(secs))
+}
+
+/// Parses a `PUBKEY=IP:PORT` endpoint assertion.
+fn parse_endpoint_assertion(assertion: &str) -> Result<([u8; 32], std::net::SocketAddr), String> {
+    // Base64 keys end in '=', so split at the last one.
+    let (key, endpoint) = assertion
+        .rsplit_once('=')
+        .ok_or_else(|| format!("{assertion}: expected PUBKEY=IP:PORT"))?;
+    let key = wg_decode_key(key).ok_or_else(|| format!("{key}: invalid public key"))?;
+    let endpoint = endpoint
+        .parse()
+        .map_err(|err| format!("{endpoint}: {err}"))?;
+    Ok((key, endpoint))
+}
+
+/// Parses a fwmark in decimal or 0x hex, with "off" for 0.
+fn parse_fwmark(mark: &str) -> Result<u32, String> {
+    let parsed = match mark.strip_prefix("0x") {
+        _ if mark == "off" => Ok(0),
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => mark.parse(),
+    };
+    parsed.map_err(|err| format!("{mark}: {err}"))
+}
+
+/// Parses a persistent keepalive interval as a duration, with "off" for 0.
+fn parse_keepalive(secs: &str) -> Result<u16, String> {
+    match secs {
+        "off" => Ok(0),
+        secs => parse_duration(secs)?
+            .as_secs()
+            .try_into()
+            .map_err(|_| format!("{secs}: keepalive too long")),
+    }
+}
+
+/// Whether two prefixes share at least one address.
+fn cidr_overlaps((a, a_prefix): (IpAddr, u8), (b, b_prefix): (IpAddr, u8)) -> bool {
+    let (a, b, bits) = match (a, b) {
+        (IpAddr::V4(a), IpAddr::V4(b)) => (u32::from(a) as u128, u32::from(b) as u128, 32),
+        (IpAddr::V6(a), IpAddr::V6(b)) => (u128::from(a), u128::from(b), 128),
+        _ => return false,
+    };
+
+    let prefix = a_prefix.min(b_prefix) as u32;
+    if prefix == 0 {
+        return true;
+    }
+
+    let shift = bits - prefix;
+    a >> shift == b >> shift
+}
+
+/// Number of addresses covered by `ips`, saturating at `u128::MAX`.
+///
+/// Overlapping prefixes are counted once per prefix.
+fn allowed_addresses(ips: &[(IpAddr, u8)]) -> u128 {
+    ips.iter()
+        .map(|(ip, prefix)| {
+            let bits = if ip.is_ipv4() { 32 } else { 128 };
+            1u128
+                .checked_shl(bits - *prefix as u32)
+                .unwrap_or(u128::MAX)
+        })
+        .fold(0, u128::saturating_add)
+}
+
+/// Prints the peers grouped by endpoint, largest groups first.
+fn print_endpoint_groups(peers: &[WgPeer], anonymize: bool) {
+    let mut groups: HashMap<Option<std::net::SocketAddr>, Vec<&WgPeer>> = HashMap::new();
+    for peer in peers {
+        groups.entry(peer.endpoint).or_default().push(peer);
+    }
+
+    let mut groups: Vec<_> = groups.into_iter().collect();
+    groups.sort_by(|(a, a_peers), (b, b_peers)| b_peers.len().cmp(&a_peers.len()).then(a.cmp(b)));
+
+    for (endpoint, peers) in groups {
+        let endpoint = match endpoint {
+            Some(endpoint) => format_endpoint(&endpoint, anonymize),
+            None => "(no endpoint)".to_owned(),
+        };
+        println!("{endpoint} ({} peers)", peers.len());
+        for peer in peers {
+            println!("  {}", format_key(&peer.public_key, anonymize));
+        }
+    }
+}
+
+/// Prints the peers grouped into handshake age buckets, most recent
+/// handshakes first within each.
+fn print_handshake_buckets(peers: &[WgPeer], now: SystemTime, anonymize: bool) {
+    const MINUTE: Duration = Duration::from_secs(60);
+    let mut buckets: [(&str, Vec<(Duration, &WgPeer)>); 5] = [
+        ("<1m", Vec::new()),
+        ("1-5m", Vec::new()),
+        ("5-30m", Vec::new()),
+        (">30m", Vec::new()),
+        ("never", Vec::new()),
+    ];
+
+    for peer in peers {
+        let Some(ts) = peer.last_handshake else {
+            buckets[4].1.push((Duration::MAX, peer));
+            continue;
+        };
+        // Handshakes in the future count as just now.
+        let age = now.duration_since(ts).unwrap_or_default();
+        let bucket = match age {
+            age if age < MINUTE => 0,
+            age if age < 5 * MINUTE => 1,
+            age if age < 30 * MINUTE => 2,
+            _ => 3,
+        };
+        buckets[bucket].1.push((age, peer));
+    }
+
+    for (name, mut peers) in buckets {
+        peers.sort_by_key(|(age, _)| *age);
+        println!("{name} ({} peers)", peers.len());
+        for (_, peer) in peers {
+            println!("  {}", format_key(&peer.public_key, anonymize));
+        }
+    }
+}
+
+/// Prints every attribute of a `GetDevice` dump in the order received.
+///
+/// Private and preshared keys are always hidden, since the output is meant
+/// for bug reports. With `anonymize`, public keys and endpoint addresses are
+/// masked as well, the same way as in the listing.
+fn print_debug_attrs(messages: &[GenlMessage<Wireguard>], anonymize: bool) {
+    let device_nla = |nla: &WgDeviceAttrs| match nla {
+        WgDeviceAttrs::PrivateKey(_) => "PrivateKey(hidden)".to_owned(),
+        WgDeviceAttrs::PublicKey(key) if anonymize => format!("PublicKey({})", anonymized_key(key)),
+        nla => format!("{nla:?}"),
+    };
+    let peer_nla = |nla: &WgPeerAttrs| match nla {
+        WgPeerAttrs::PublicKey(key) if anonymize => format!("PublicKey({})", anonymized_key(key)),
+        WgPeerAttrs::PresharedKey(_) => "PresharedKey(hidden)".to_owned(),
+        WgPeerAttrs::Endpoint(endpoint) if anonymize => {
+            format!("Endpoint({})", format_endpoint(endpoint, true))
+        }
+        nla => format!("{nla:?}"),
+    };
+
+    println!("# --debug-attrs output is unstable and may change at any time");
+    for (i, msg) in messages.iter().enumerate() {
+        println!("message {i}:");
+        for nla in msg.payload.nlas.iter() {
+            let WgDeviceAttrs::Peers(peers) = nla else {
+                println!("  {}", device_nla(nla));
+                continue;
+            };
+            println!("  Peers:");
+            for (i, peer) in peers.iter().enumerate() {
+                println!("    peer {i}:");
+                for nla in peer.iter() {
+                    let WgPeerAttrs::AllowedIps(ips) = nla else {
+                        println!("      {}", peer_nla(nla));
+                        continue;
                     };
+                    println!("      AllowedIps:");
+                    for (i, ip) in ips.iter().enumerate() {
+                        println!("        allowed ip {i}:");
+                        for nla in ip.iter() {
+                            println!("          {nla:?}");
+                        }
+                    }
                 }
-                _ => {}
             }
+        }
+    }
+}
+
+/// Quotes a CSV field if it contains a separator, quote or line break.
+fn csv_field(field: &str) -> std::borrow::Cow<'_, str> {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\"")).into()
+    } else {
+        field.into()
+    }
+}
+
+/// Key for `--sort allowed-ip`. Peers without allowed ips sort last.
+fn allowed_ip_order(
+    allowed_ips: &[(IpAddr, u8)],
+    public_key: [u8; 32],
+) -> (bool, Option<(IpAddr, u8)>, [u8; 32]) {
+    let lowest = allowed_ips.iter().min().copied();
+    (lowest.is_none(), lowest, public_key)
+}
+
+fn sort_peers(peers: &mut [WgPeer], sort: Option<Sort>) {
+    if let Some(Sort::AllowedIp) = sort {
+        peers.sort_by_cached_key(|peer| allowed_ip_order(&peer.allowed_ips, peer.public_key));
+    }
+}
+
+/// The `--json` form of a device. Keys are base64 like everywhere else, and
+/// times are seconds since the epoch.
+///
+/// The totals and endpoint counts cover the listed peers, even with
+/// --no-peers. With --show-zero, a peer that never completed a handshake has
+/// a `last_handshake` of 0 instead of null. With --iso8601-duration,
+/// `last_handshake` is instead the age of the handshake, such as "PT2M13S".
+#[derive(serde::Serialize)]
+struct JsonDevice {
+    interface: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    present: Option<bool>,
+    public_key: Option<String>,
+    has_private_key: bool,
+    listen_port: u16,
+    fwmark: u32,
+    total_rx: u64,
+    total_tx: u64,
+    endpoints: JsonEndpoints,
+    peers: Vec<JsonPeer>,
+}
+
+#[derive(serde::Serialize)]
+struct JsonEndpoints {
+    ipv4: usize,
+    ipv6: usize,
+    none: usize,
+}
+
+#[derive(serde::Serialize)]
+struct JsonPeer {
+    public_key: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    label: Option<String>,
+    has_preshared_key: bool,
+    endpoint: Option<String>,
+    allowed_ips: Vec<String>,
+    allowed_ips_count: usize,
+    persistent_keepalive: u16,
+    last_handshake: Option<JsonHandshake>,
+    rx_bytes: u64,
+    tx_bytes: u64,
+    /// With --fail-on-stale, whether the peer is stale
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stale: Option<bool>,
+}
 
-            offset += packet.header.length as usize;
-            if offset == len || packet.header.length == 0 {
-                offset = 0;
-                break;
+#[derive(serde::Serialize)]
+#[serde(untagged)]
+enum JsonHandshake {
+    Time(u64),
+    Age(String),
+}
+
+impl JsonDevice {
+    /// The JSON form of `device`, whose peers are already filtered and
+    /// sorted.
+    fn new(
+        device: &WgDevice,
+        labels: &HashMap<String, String>,
+        now: SystemTime,
+        args: &ShowArgs,
+    ) -> Self {
+        let anonymize = args.anonymize;
+        let last_handshake = |ts: Option<SystemTime>| match ts {
+            Some(ts) if args.iso8601_duration => {
+                Some(JsonHandshake::Age(format_handshake_age(now, ts, true)))
             }
+            Some(ts) => {
+                let ts = ts.duration_since(UNIX_EPOCH).unwrap_or_default();
+                Some(JsonHandshake::Time(ts.as_secs()))
+            }
+            None if args.show_zero && !args.iso8601_duration => Some(JsonHandshake::Time(0)),
+            None => None,
+        };
+        let (total_rx, total_tx) = transfer_totals(&device.peers);
+        let (ipv4, ipv6, none) = endpoint_families(&device.peers);
+        let peers = if args.no_peers {
+            &[][..]
+        } else {
+            &device.peers
+        };
+
+        Self {
+            interface: device.name.clone(),
+            present: args.missing_ok.then_some(true),
+            public_key: device.public_key.map(|key| format_key(&key, anonymize)),
+            has_private_key: device.private_key.is_some(),
+            listen_port: device.listen_port,
+            fwmark: device.fwmark,
+            total_rx,
+            total_tx,
+            endpoints: JsonEndpoints { ipv4, ipv6, none },
+            peers: peers
+                .iter()
+                .map(|peer| JsonPeer {
+                    public_key: format_key(&peer.public_key, anonymize),
+                    label: labels.get(&wg_public_key(&peer.public_key)).cloned(),
+                    has_preshared_key: peer.preshared_key.is_some(),
+                    endpoint: peer
+                        .endpoint
+                        .map(|endpoint| format_endpoint(&endpoint, anonymize)),
+                    allowed_ips: peer
+                        .allowed_ips
+                        .iter()
+                        .map(|(ip, prefix)| format!("{ip}/{prefix}"))
+                        .collect(),
+                    allowed_ips_count: peer.allowed_ips.len(),
+                    persistent_keepalive: peer.persistent_keepalive,
+                    last_handshake: last_handshake(peer.last_handshake),
+                    rx_bytes: peer.rx_bytes,
+                    tx_bytes: peer.tx_bytes,
+                    stale: args
+                        .fail_on_stale
+                        .then(|| is_stale(peer, now, args.active_within)),
+                })
+                .collect(),
         }
     }
 }
 
-fn wg_allowed_ips(ips: Vec<WgAllowedIp>) -> Vec<String> {
-    ips.into_iter()
-        .filter_map(|ip| {
-            let mut found_ip = None;
-            let mut found_cidr = None;
+/// Prints a `--json` document on a line, or with --msgpack as MessagePack.
+fn print_document(document: &serde_json::Value, msgpack: bool) -> std::io::Result<()> {
+    use std::io::Write as _;
+
+    let mut stdout = std::io::stdout().lock();
+    if msgpack {
+        let mut bytes = Vec::new();
+        encode_msgpack(document, &mut bytes);
+        stdout.write_all(&bytes)?;
+        stdout.flush()
+    } else {
+        writeln!(stdout, "{document}")
+    }
+}
+
+/// Appends `value` to `out` as MessagePack, in the smallest encoding for
+/// each value. Objects become maps with string keys.
+///
+/// This covers all serde_json can hold, which is all --msgpack needs, so it
+/// doesn't take another dependency.
+fn encode_msgpack(value: &serde_json::Value, out: &mut Vec<u8>) {
+    use serde_json::Value;
 
-            for attr in ip.0.into_iter() {
-                match attr {
-                    WgAllowedIpAttrs::IpAddr(ip) => found_ip = Some(ip),
-                    WgAllowedIpAttrs::Cidr(cidr) => found_cidr = Some(cidr),
-                    _ => {}
+    // The marker for lengths that fit 8 bits, if the type has one, then
+    // those for 16 and 32 bits.
+    fn header(out: &mut Vec<u8>, len: usize, markers: (Option<u8>, u8, u8)) {
+        match (len, markers.0) {
+            (0..=0xff, Some(marker)) => out.extend([marker, len as u8]),
+            (0..=0xffff, _) => {
+                out.push(markers.1);
+                out.extend((len as u16).to_be_bytes());
+            }
+            _ => {
+                out.push(markers.2);
+                out.extend((len as u32).to_be_bytes());
+            }
+        }
+    }
+
+    match value {
+        Value::Null => out.push(0xc0),
+        Value::Bool(false) => out.push(0xc2),
+        Value::Bool(true) => out.push(0xc3),
+        Value::Number(number) => {
+            if let Some(n) = number.as_u64() {
+                match n {
+                    0..=0x7f => out.push(n as u8),
+                    0x80..=0xff => out.extend([0xcc, n as u8]),
+                    0x100..=0xffff => {
+                        out.push(0xcd);
+                        out.extend((n as u16).to_be_bytes());
+                    }
+                    0x1_0000..=0xffff_ffff => {
+                        out.push(0xce);
+                        out.extend((n as u32).to_be_bytes());
+                    }
+                    _ => {
+                        out.push(0xcf);
+                        out.extend(n.to_be_bytes());
+                    }
                 }
+            } else if let Some(n) = number.as_i64() {
+                // Only negative numbers are left here.
+                match n {
+                    -32..=-1 => out.push(n as u8),
+                    -0x80..=-33 => out.extend([0xd0, n as u8]),
+                    -0x8000..=-0x81 => {
+                        out.push(0xd1);
+                        out.extend((n as i16).to_be_bytes());
+                    }
+                    -0x8000_0000..=-0x8001 => {
+                        out.push(0xd2);
+                        out.extend((n as i32).to_be_bytes());
+                    }
+                    _ => {
+                        out.push(0xd3);
+                        out.extend(n.to_be_bytes());
+                    }
+                }
+            } else {
+                out.push(0xcb);
+                out.extend(number.as_f64().unwrap_or_default().to_be_bytes());
+            }
+        }
+        Value::String(string) => {
+            match string.len() {
+                len @ 0..=31 => out.push(0xa0 | len as u8),
+                len => header(out, len, (Some(0xd9), 0xda, 0xdb)),
+            }
+            out.extend(string.as_bytes());
+        }
+        Value::Array(values) => {
+            match values.len() {
+                len @ 0..=15 => out.push(0x90 | len as u8),
+                len => header(out, len, (None, 0xdc, 0xdd)),
             }
-            let found_ip = found_ip?;
-            let found_cidr = found_cidr?;
+            values.iter().for_each(|value| encode_msgpack(value, out));
+        }
+        Value::Object(map) => {
+            match map.len() {
+                len @ 0..=15 => out.push(0x80 | len as u8),
+                len => header(out, len, (None, 0xde, 0xdf)),
+            }
+            for (key, value) in map {
+                encode_msgpack(&Value::String(key.clone()), out);
+                encode_msgpack(value, out);
+            }
+        }
+    }
+}
+
+/// The `--json` form of a device as `--ndjson` lines: the device without
+/// its peers, then each peer with the device's interface name.
+fn ndjson_lines(mut device: serde_json::Value) -> Vec<String> {
+    let peers = device
+        .as_object_mut()
+        .and_then(|device| device.remove("peers"));
+    let mut lines = vec![device.to_string()];
+    if let Some(serde_json::Value::Array(peers)) = peers {
+        for mut peer in peers {
+            peer["interface"] = device["interface"].clone();
+            lines.push(peer.to_string());
+        }
+    }
+    lines
+}
+
+/// Whether `peer` had no handshake in the `active_within` before `now`.
+fn is_stale(peer: &WgPeer, now: SystemTime, active_within: Duration) -> bool {
+    peer.last_handshake
+        .is_none_or(|ts| now.duration_since(ts).unwrap_or_default() > active_within)
+}
+
+/// With --fail-on-stale, fails if any of `peers` is stale.
+fn check_stale(peers: &[WgPeer], now: SystemTime, args: &ShowArgs) -> std::io::Result<()> {
+    if !args.fail_on_stale {
+        return Ok(());
+    }
+    match peers
+        .iter()
+        .filter(|peer| is_stale(peer, now, args.active_within))
+        .count()
+    {
+        0 => Ok(()),
+        stale => Err(std::io::Error::other(format!("{stale} stale peers"))),
+    }
+}
 
-            Some(format!("{found_ip}/{found_cidr}"))
+/// Bytes received and sent, summed over `peers`.
+fn transfer_totals(peers: &[WgPeer]) -> (u64, u64) {
+    peers.iter().fold((0, 0), |(rx, tx), peer| {
+        (
+            rx.saturating_add(peer.rx_bytes),
+            tx.saturating_add(peer.tx_bytes),
+        )
+    })
+}
+
+/// How many of `peers` have an IPv4, an IPv6 or no endpoint.
+fn endpoint_families(peers: &[WgPeer]) -> (usize, usize, usize) {
+    peers
+        .iter()
+        .fold((0, 0, 0), |(v4, v6, none), peer| match peer.endpoint {
+            Some(std::net::SocketAddr::V4(_)) => (v4 + 1, v6, none),
+            Some(std::net::SocketAddr::V6(_)) => (v4, v6 + 1, none),
+            None => (v4, v6, none + 1),
         })
+}
+
+fn export_peers(export: Export, peers: &[WgPeer], anonymize: bool) {
+    match export {
+        Export::Hosts => {
+            for peer in peers {
+                let Some((ip, _)) = peer.allowed_ips.first() else {
+                    continue;
+                };
+                let key = if anonymize {
+                    anonymized_key(&peer.public_key)
+                } else {
+                    wg_public_key(&peer.public_key)[..HOSTS_KEY_PREFIX_LEN].to_owned()
+                };
+                println!("{ip} {key}");
+            }
+        }
+        Export::Csv => {
+            println!("pubkey,endpoint,allowed_ips,last_handshake,rx,tx");
+            for peer in peers {
+                let endpoint = peer
+                    .endpoint
+                    .map(|endpoint| format_endpoint(&endpoint, anonymize))
+                    .unwrap_or_default();
+                let last_handshake = peer
+                    .last_handshake
+                    .and_then(|ts| ts.duration_since(UNIX_EPOCH).ok())
+                    .map(|ts| ts.as_secs().to_string())
+                    .unwrap_or_default();
+                println!(
+                    "{},{},{},{},{},{}",
+                    format_key(&peer.public_key, anonymize),
+                    csv_field(&endpoint),
+                    csv_field(&format_allowed_ips(&peer.allowed_ips)),
+                    last_handshake,
+                    peer.rx_bytes,
+                    peer.tx_bytes,
+                );
+            }
+        }
+    }
+}
+
+/// `pretty_time()` from wg(8): "1 hour, 5 seconds".
+fn wg_pretty_time(secs: u64) -> String {
+    let units = [
+        (secs / (365 * 24 * 60 * 60), "year"),
+        (secs % (365 * 24 * 60 * 60) / (24 * 60 * 60), "day"),
+        (secs % (24 * 60 * 60) / (60 * 60), "hour"),
+        (secs % (60 * 60) / 60, "minute"),
+        (secs % 60, "second"),
+    ];
+
+    units
+        .iter()
+        .filter(|(n, _)| *n != 0)
+        .map(|(n, unit)| format!("{n} {unit}{}", if *n == 1 { "" } else { "s" }))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// `bytes()` from wg(8): "1.50 KiB".
+fn wg_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut scaled = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while unit < UNITS.len() - 1 && bytes >= 1 << (10 * (unit + 2)) {
+        scaled /= 1024.0;
+        unit += 1;
+    }
+    format!("{scaled:.2} {}", UNITS[unit])
+}
+
+/// Reads a file of `PUBKEY VALUE` lines, as used by `--labels` and
+/// `refresh-endpoints`, skipping blank lines and `#` comments.
+fn read_key_file(path: &std::path::Path) -> std::io::Result<Vec<(String, String)>> {
+    let labels = std::fs::read_to_string(path)
+        .map_err(|err| std::io::Error::new(err.kind(), format!("{}: {err}", path.display())))?;
+
+    Ok(labels
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_once(char::is_whitespace))
+        .map(|(key, label)| (key.to_owned(), label.trim().to_owned()))
+        .collect())
+}
+
+/// Prints an `ip route add` command for each distinct allowed ip of the
+/// device.
+fn print_routes_script(device: &WgDevice, interface: &OsStr) {
+    let name = match &device.name {
+        Some(name) => name.clone(),
+        None => interface.to_string_lossy().into_owned(),
+    };
+
+    let mut seen = HashSet::new();
+    for &(ip, prefix) in device.peers.iter().flat_map(|peer| &peer.allowed_ips) {
+        if seen.insert((ip, prefix)) {
+            println!("ip route add {ip}/{prefix} dev {name}");
+        }
+    }
+}
+
+/// Writes a device the way `wg show <interface>` does, without colors.
+///
+/// With `anonymize`, keys and endpoints are masked like in the listing.
+fn write_wg_compat(
+    out: &mut impl std::io::Write,
+    device: &WgDevice,
+    now: SystemTime,
+    anonymize: bool,
+) -> std::io::Result<()> {
+    // wg(8) treats all-zero keys as unset.
+    let is_set = |key: &&[u8; 32]| **key != [0; 32];
+
+    if let Some(name) = &device.name {
+        writeln!(out, "interface: {name}")?;
+    }
+    if let Some(key) = device.public_key.as_ref().filter(is_set) {
+        writeln!(out, "  public key: {}", format_key(key, anonymize))?;
+    }
+    if device.private_key.as_ref().filter(is_set).is_some() {
+        writeln!(out, "  private key: (hidden)")?;
+    }
+    if device.listen_port != 0 {
+        writeln!(out, "  listening port: {}", device.listen_port)?;
+    }
+    if device.fwmark != 0 {
+        writeln!(out, "  fwmark: 0x{:x}", device.fwmark)?;
+    }
+
+    // wg(8) lists the most recent handshakes first and peers without one last.
+    let mut peers: Vec<_> = device.peers.iter().collect();
+    peers.sort_by_key(|peer| std::cmp::Reverse(peer.last_handshake));
+
+    let now = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    for peer in peers {
+        writeln!(out, "\npeer: {}", format_key(&peer.public_key, anonymize))?;
+        if peer.preshared_key.is_some() {
+            writeln!(out, "  preshared key: (hidden)")?;
+        }
+        if let Some(endpoint) = peer.endpoint {
+            writeln!(out, "  endpoint: {}", format_endpoint(&endpoint, anonymize))?;
+        }
+        if peer.allowed_ips.is_empty() {
+            writeln!(out, "  allowed ips: (none)")?;
+        } else {
+            writeln!(
+                out,
+                "  allowed ips: {}",
+                format_allowed_ips(&peer.allowed_ips)
+            )?;
+        }
+        if let Some(ts) = peer.last_handshake {
+            let ts = ts.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+            let age = match now.checked_sub(ts) {
+                Some(0) => "Now".to_owned(),
+                Some(age) => format!("{} ago", wg_pretty_time(age)),
+                None => "(System clock wound backward; connection problems may ensue.)".to_owned(),
+            };
+            writeln!(out, "  latest handshake: {age}")?;
+        }
+        if peer.rx_bytes != 0 || peer.tx_bytes != 0 {
+            let (rx, tx) = (wg_bytes(peer.rx_bytes), wg_bytes(peer.tx_bytes));
+            writeln!(out, "  transfer: {rx} received, {tx} sent")?;
+        }
+        if peer.persistent_keepalive != 0 {
+            let every = wg_pretty_time(peer.persistent_keepalive.into());
+            writeln!(out, "  persistent keepalive: every {every}")?;
+        }
+    }
+    Ok(())
+}
+
+/// Curve25519 public key for `private_key`, per RFC 7748.
+///
+/// The scalar is clamped before multiplication, so unclamped private keys
+/// give the same result as their clamped form.
+fn x25519_public(private_key: &[u8; 32]) -> [u8; 32] {
+    x25519_dalek::x25519(*private_key, x25519_dalek::X25519_BASEPOINT_BYTES)
+}
+
+/// Whether `key` is clamped for Curve25519 as RFC 7748 describes: the low
+/// three bits of the first byte clear, and of the last byte the top bit
+/// clear and the one below it set.
+fn is_clamped(key: &[u8; 32]) -> bool {
+    key[0] & 7 == 0 && key[31] & 0xc0 == 0x40
+}
+
+fn clamp(mut key: [u8; 32]) -> [u8; 32] {
+    key[0] &= 248;
+    key[31] &= 127;
+    key[31] |= 64;
+    key
+}
+
+/// Clamps a private key from `source` with `--clamp`, or warns that it
+/// isn't clamped.
+fn check_private_key(
+    key: [u8; 32],
+    checks: InputChecks,
+    source: impl std::fmt::Display,
+) -> [u8; 32] {
+    if is_clamped(&key) {
+        key
+    } else if checks.clamp {
+        clamp(key)
+    } else {
+        eprintln!(
+            "warning: {source}: private key is not clamped for Curve25519, \
+             its public key may differ between implementations (--clamp clamps it)"
+        );
+        key
+    }
+}
+
+/// `ip` with the bits past `prefix` cleared.
+fn network_address(ip: IpAddr, prefix: u8) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0);
+            IpAddr::V4((u32::from(ip) & mask).into())
+        }
+        IpAddr::V6(ip) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(prefix)).unwrap_or(0);
+            IpAddr::V6((u128::from(ip) & mask).into())
+        }
+    }
+}
+
+/// Clears host bits in allowed ips from `source`, warning about them, or
+/// rejects them with `--strict`.
+fn check_allowed_ips(
+    ips: &mut [(IpAddr, u8)],
+    checks: InputChecks,
+    source: impl std::fmt::Display,
+) -> Result<(), Error> {
+    for (ip, prefix) in ips {
+        let network = network_address(*ip, *prefix);
+        if network == *ip {
+            continue;
+        }
+        if checks.strict {
+            return Err(Error::Invalid(format!(
+                "{ip}/{prefix} has host bits set, did you mean {network}/{prefix}?"
+            )));
+        }
+        eprintln!("warning: {source}: {ip}/{prefix} has host bits set, using {network}/{prefix}");
+        *ip = network;
+    }
+    Ok(())
+}
+
+/// Generates a private key from the OS random source, clamped the same
+/// way as `wg genkey`.
+fn wg_generate_private_key() -> std::io::Result<[u8; 32]> {
+    let mut key = [0u8; 32];
+    getrandom::getrandom(&mut key)?;
+    Ok(clamp(key))
+}
+
+/// Generates a preshared key from the OS random source. These are plain
+/// symmetric keys, so unlike private keys there is no clamping.
+fn wg_generate_preshared_key() -> std::io::Result<[u8; 32]> {
+    let mut key = [0u8; 32];
+    getrandom::getrandom(&mut key)?;
+    Ok(key)
+}
+
+/// Short stand-in for a public key in `--anonymize` output: the first
+/// 6 hex digits of its SHA-256, so the same key always maps the same way.
+fn anonymized_key(key: &[u8; 32]) -> String {
+    use sha2::Digest as _;
+    let digest = sha2::Sha256::digest(key);
+    digest[..3]
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
         .collect()
 }
 
-fn wg_public_key(key: &[u8; 32]) -> String {
-    use base64::Engine as _;
-    base64::engine::general_purpose::STANDARD.encode(key)
+fn format_key(key: &[u8; 32], anonymize: bool) -> String {
+    if anonymize {
+        anonymized_key(key)
+    } else {
+        wg_public_key(key)
+    }
 }
 
-#[derive(clap::Parser)]
-struct Args {
-    interface: String,
+fn format_endpoint(endpoint: &std::net::SocketAddr, anonymize: bool) -> String {
+    if anonymize {
+        format!("*:{}", endpoint.port())
+    } else {
+        endpoint.to_string()
+    }
 }
 
-fn main() {
-    env_logger::init();
+/// First file descriptor passed by systemd socket activation (`SD_LISTEN_FDS_START`).
+const LISTEN_FDS_START: RawFd = 3;
 
-    let Args { interface } = clap::Parser::parse();
+/// Finds the socket-activated file descriptor named `name`, following the
+/// `sd_listen_fds_with_names(3)` conventions.
+fn listen_fd_by_name(name: &str) -> std::io::Result<RawFd> {
+    let not_found = || {
+        std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("no socket-activated fd named {name:?}"),
+        )
+    };
 
-    let mut generic = Socket::new(NETLINK_GENERIC).unwrap();
+    let var = |name| std::env::var(name).map_err(|_| not_found());
 
-    let mut addr = SocketAddr::new(0, 0);
-    generic.bind(&addr).unwrap();
-    generic.get_address(&mut addr).unwrap();
-
-    let mut message = NetlinkMessage::from(GenlMessage::from_payload(GenlCtrl {
-        cmd: GenlCtrlCmd::GetFamily,
-        nlas: vec![GenlCtrlAttrs::FamilyName(
-            Wireguard::family_name().to_owned(),
-        )],
-    }));
-    message.header.flags = (NLM_F_REQUEST | NLM_F_ACK) as _;
-    message.finalize();
+    if var("LISTEN_PID")?.parse() != Ok(std::process::id()) {
+        return Err(not_found());
+    }
+    let fds: usize = var("LISTEN_FDS")?.parse().map_err(|_| not_found())?;
 
-    socket_send(&mut generic, &message).unwrap();
+    let index = var("LISTEN_FDNAMES")?
+        .split(':')
+        .take(fds)
+        .position(|fd_name| fd_name == name)
+        .ok_or_else(not_found)?;
 
-    let messages = socket_recv::<GenlMessage<GenlCtrl>>(&mut generic).unwrap();
+    Ok(LISTEN_FDS_START + index as RawFd)
+}
 
-    let family_id = messages
-        .into_iter()
-        .flat_map(|msg| msg.payload.nlas.into_iter())
-        .find_map(|attr| match attr {
-            GenlCtrlAttrs::FamilyId(id) => Some(id),
-            _ => None,
-        });
+/// Opens the generic netlink socket, or takes over the socket-activated one
+/// named `fd_name`.
+///
+/// A `port_id` of 0 lets the kernel pick a free port id.
+fn wg_socket(fd_name: Option<&str>, port_id: u32) -> std::io::Result<Socket> {
+    let Some(fd_name) = fd_name else {
+        let mut socket = Socket::new(NETLINK_GENERIC)?;
+
+        let mut addr = SocketAddr::new(port_id, 0);
+        socket.bind(&addr)?;
+        socket.get_address(&mut addr)?;
+
+        return Ok(socket);
+    };
+
+    let fd = listen_fd_by_name(fd_name)?;
+
+    let mut protocol: libc::c_int = 0;
+    let mut len = std::mem::size_of_val(&protocol) as libc::socklen_t;
+    // SAFETY: `protocol` and `len` describe a valid, writable c_int.
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_PROTOCOL,
+            &mut protocol as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    if protocol as isize != NETLINK_GENERIC {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("socket-activated fd {fd_name:?} is not a generic netlink socket"),
+        ));
+    }
+
+    // SAFETY: socket activation hands ownership of the passed fds to this
+    // process, and the fd was checked to be a generic netlink socket, which
+    // the activating unit has already bound.
+    Ok(unsafe { Socket::from_raw_fd(fd) })
+}
+
+fn setsockopt(
+    socket: &Socket,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> std::io::Result<()> {
+    // SAFETY: `value` is a valid c_int for the duration of the call.
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &value as *const _ as *const libc::c_void,
+            std::mem::size_of_val(&value) as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Sets the socket receive buffer to `size` bytes.
+///
+/// `SO_RCVBUFFORCE` is tried first so privileged callers can exceed
+/// `net.core.rmem_max`; without `CAP_NET_ADMIN` this falls back to
+/// `SO_RCVBUF`, which the kernel caps at `rmem_max`. Either way the kernel
+/// doubles the requested value to account for its own bookkeeping.
+fn set_rcvbuf(socket: &Socket, size: libc::c_int) -> std::io::Result<()> {
+    match setsockopt(socket, libc::SOL_SOCKET, libc::SO_RCVBUFFORCE, size) {
+        Err(err) if err.raw_os_error() == Some(libc::EPERM) => {
+            setsockopt(socket, libc::SOL_SOCKET, libc::SO_RCVBUF, size)
+        }
+        ret => ret,
+    }
+}
 
-    let family_id = family_id.unwrap();
+async fn wg_set_device(
+    socket: &mut Async<Socket>,
+    family_id: u16,
+    interface: &OsStr,
+    mut nlas: Vec<WgDeviceAttrs>,
+) -> std::io::Result<()> {
+    nlas.insert(0, wg_device_selector(interface)?);
 
     let mut message = NetlinkMessage::from(GenlMessage::from_payload(Wireguard {
-        cmd: WireguardCmd::GetDevice,
-        nlas: vec![WgDeviceAttrs::IfName(interface.clone())],
+        cmd: WireguardCmd::SetDevice,
+        nlas,
     }));
-    message.header.flags = (NLM_F_REQUEST | NLM_F_ACK | NLM_F_DUMP) as _;
+    message.header.flags = (NLM_F_REQUEST | NLM_F_ACK) as _;
     let NetlinkPayload::InnerMessage(ref mut payload) = message.payload else {
         panic!();
     };
     payload.set_resolved_family_id(family_id);
     message.finalize();
 
-    socket_send(&mut generic, &message).unwrap();
-
-    let messages = socket_recv::<GenlMessage<Wireguard>>(&mut generic).unwrap();
-
-    for msg in messages.into_iter() {
-        for nlas in msg.payload.nlas.into_iter() {
-            match nlas {
-                WgDeviceAttrs::IfName(iface) => println!("Interface: {iface}"),
-                WgDeviceAttrs::PrivateKey(_) => println!("Private key: (hidden)"),
-                WgDeviceAttrs::PublicKey(key) => println!("Public key: {}", wg_public_key(&key)),
-                WgDeviceAttrs::ListenPort(port) => println!("Listen port: {port}"),
-                WgDeviceAttrs::Fwmark(fwmark) if fwmark != 0 => println!("FwMark: {fwmark}"),
-                WgDeviceAttrs::Peers(peers) => peers.into_iter().for_each(|peer| {
-                    println!("\nPeer:");
-                    for nlas in peer.0.into_iter() {
-                        match nlas {
-                            WgPeerAttrs::PublicKey(key) => {
-                                println!("  Public key: {}", wg_public_key(&key))
-                            }
-                            WgPeerAttrs::PresharedKey(_) => println!("  Preshared key: (hidden)"),
-                            WgPeerAttrs::Endpoint(endpoint) => println!("  Endpoint: {endpoint}"),
-                            WgPeerAttrs::AllowedIps(ips) => {
-                                let ips = wg_allowed_ips(ips).join(", ");
-                                println!("  Allowed ips: {ips}")
-                            }
-                            WgPeerAttrs::PersistentKeepalive(keep_alive) if keep_alive != 0 => {
-                                println!("  KeepAlive: {keep_alive}")
-                            }
-                            WgPeerAttrs::LastHandshake(ts) if ts != UNIX_EPOCH => {
-                                match ts.elapsed() {
-                                    Ok(elapsed) => {
-                                        let elapsed = elapsed.as_secs();
-                                        println!("  Last handshake: {elapsed}s ago")
-                                    }
-                                    Err(err) => log::error!("{err}"),
-                                }
-                            }
-
-                            _ => {}
-                        }
-                    }
-                }),
-                _ => {}
+    socket_send(socket, &message).await?;
+
+    socket_recv::<GenlMessage<Wireguard>>(socket).await?;
+
+    Ok(())
+}
+
+/// Like [`wg_set_device`], but spreads the peers over as many requests as
+/// they need; see [`split_set_device`]. The requests are applied one by one,
+/// so if one fails, the ones before it have taken effect.
+async fn wg_set_device_split(
+    socket: &mut Async<Socket>,
+    family_id: u16,
+    interface: &OsStr,
+    nlas: Vec<WgDeviceAttrs>,
+) -> std::io::Result<()> {
+    for nlas in split_set_device(nlas) {
+        wg_set_device(socket, family_id, interface, nlas).await?;
+    }
+    Ok(())
+}
+
+/// Splits SetDevice attributes so the peers of each request stay within
+/// [`SET_DEVICE_PEERS_LEN`], like wg(8) does.
+///
+/// The device attributes, including a `REPLACE_PEERS` flag, only go in the
+/// first request, so the peers are replaced once, before any are added. A
+/// peer whose allowed ips don't fit in one request continues in the next as
+/// just its public key and the rest of its allowed ips, which the kernel
+/// appends.
+fn split_set_device(nlas: Vec<WgDeviceAttrs>) -> Vec<Vec<WgDeviceAttrs>> {
+    let mut requests = vec![Vec::new()];
+    let mut peers = Vec::new();
+    for nla in nlas {
+        match nla {
+            WgDeviceAttrs::Peers(more) => peers.extend(more),
+            nla => requests[0].push(nla),
+        }
+    }
+
+    let mut batch = Vec::new();
+    let mut len = 0;
+    for entry in peers.into_iter().flat_map(split_peer) {
+        let entry_len = entry.buffer_len();
+        if len + entry_len > SET_DEVICE_PEERS_LEN && !batch.is_empty() {
+            let last = requests.last_mut().expect("there is a first request");
+            last.push(WgDeviceAttrs::Peers(std::mem::take(&mut batch)));
+            requests.push(Vec::new());
+            len = 0;
+        }
+        len += entry_len;
+        batch.push(entry);
+    }
+    if !batch.is_empty() {
+        let last = requests.last_mut().expect("there is a first request");
+        last.push(WgDeviceAttrs::Peers(batch));
+    }
+    requests
+}
+
+/// Splits a peer entry with more allowed ips than fit in one request into
+/// entries that each do; the ones after the first only carry the public key.
+fn split_peer(peer: nlas::WgPeer) -> Vec<nlas::WgPeer> {
+    if peer.buffer_len() <= SET_DEVICE_PEERS_LEN {
+        return vec![peer];
+    }
+
+    let mut first = Vec::new();
+    let mut ips = Vec::new();
+    let mut key = None;
+    for nla in peer.0 {
+        match nla {
+            WgPeerAttrs::AllowedIps(more) => ips.extend(more),
+            nla => {
+                if let WgPeerAttrs::PublicKey(public_key) = nla {
+                    key = Some(public_key);
+                }
+                first.push(nla);
             }
         }
     }
+    let Some(key) = key else {
+        // The kernel rejects a peer without a key anyway.
+        first.push(WgPeerAttrs::AllowedIps(ips));
+        return vec![nlas::WgPeer(first)];
+    };
+
+    let mut entries = Vec::new();
+    let mut entry = first;
+    let mut chunk = Vec::new();
+    // The attribute headers of the entry and of its allowed ip list.
+    let mut len = entry.iter().map(|nla| nla.buffer_len()).sum::<usize>() + 8;
+    for ip in ips {
+        let ip_len = ip.buffer_len();
+        if len + ip_len > SET_DEVICE_PEERS_LEN && !chunk.is_empty() {
+            entry.push(WgPeerAttrs::AllowedIps(std::mem::take(&mut chunk)));
+            entries.push(nlas::WgPeer(entry));
+            entry = vec![WgPeerAttrs::PublicKey(key)];
+            len = entry[0].buffer_len() + 8;
+        }
+        len += ip_len;
+        chunk.push(ip);
+    }
+    entry.push(WgPeerAttrs::AllowedIps(chunk));
+    entries.push(nlas::WgPeer(entry));
+    entries
+}
+
+fn rt_socket() -> std::io::Result<Async<Socket>> {
+    let mut socket = Socket::new(NETLINK_ROUTE)?;
+
+    let mut addr = SocketAddr::new(0, 0);
+    socket.bind(&addr)?;
+    socket.get_address(&mut addr)?;
+
+    Async::new(socket)
+}
+
+async fn rt_get_link(
+    socket: &mut Async<Socket>,
+    index: u32,
+) -> std::io::Result<Option<LinkMessage>> {
+    let mut link = LinkMessage::default();
+    link.header.index = index;
+
+    let mut message = NetlinkMessage::from(RouteNetlinkMessage::GetLink(link));
+    message.header.flags = (NLM_F_REQUEST | NLM_F_ACK) as _;
+    message.finalize();
+
+    socket_send(socket, &message).await?;
+
+    let messages = socket_recv::<RouteNetlinkMessage>(socket).await?;
+
+    Ok(messages.into_iter().find_map(|msg| match msg {
+        RouteNetlinkMessage::NewLink(link) => Some(link),
+        _ => None,
+    }))
+}
+
+/// Whether `link` has the wireguard link kind.
+fn is_wireguard(link: &LinkMessage) -> bool {
+    link.attributes.iter().any(|attr| match attr {
+        LinkAttribute::LinkInfo(infos) => infos.contains(&LinkInfo::Kind(InfoKind::Wireguard)),
+        _ => false,
+    })
+}
+
+/// Dumps all links and returns the names of the wireguard ones.
+async fn rt_wireguard_links(socket: &mut Async<Socket>) -> std::io::Result<Vec<String>> {
+    let mut message = NetlinkMessage::from(RouteNetlinkMessage::GetLink(LinkMessage::default()));
+    message.header.flags = (NLM_F_REQUEST | NLM_F_DUMP) as _;
+    message.finalize();
+
+    socket_send(socket, &message).await?;
+
+    let messages = socket_recv::<RouteNetlinkMessage>(socket).await?;
+
+    Ok(messages
+        .into_iter()
+        .filter_map(|msg| match msg {
+            RouteNetlinkMessage::NewLink(link) => Some(link),
+            _ => None,
+        })
+        .filter(is_wireguard)
+        .filter_map(|link| {
+            link.attributes.into_iter().find_map(|attr| match attr {
+                LinkAttribute::IfName(name) => Some(name),
+                _ => None,
+            })
+        })
+        .collect())
+}
+
+/// Creates a wireguard link called `name`.
+async fn rt_add_wireguard_link(socket: &mut Async<Socket>, name: &str) -> std::io::Result<()> {
+    let mut link = LinkMessage::default();
+    link.attributes.push(LinkAttribute::IfName(name.to_owned()));
+    link.attributes
+        .push(LinkAttribute::LinkInfo(vec![LinkInfo::Kind(
+            InfoKind::Wireguard,
+        )]));
+
+    let mut message = NetlinkMessage::from(RouteNetlinkMessage::NewLink(link));
+    message.header.flags = (NLM_F_REQUEST | NLM_F_ACK | NLM_F_CREATE | NLM_F_EXCL) as _;
+    message.finalize();
+
+    socket_send(socket, &message).await?;
+
+    socket_recv::<RouteNetlinkMessage>(socket).await?;
+
+    Ok(())
+}
+
+/// Deletes `interface`, refusing with EOPNOTSUPP if it isn't a wireguard
+/// link, since RTM_DELLINK itself would delete any kind.
+async fn rt_del_wireguard_link(
+    socket: &mut Async<Socket>,
+    interface: &OsStr,
+) -> std::io::Result<()> {
+    let index = if_index(interface)?;
+    let is_wg = rt_get_link(socket, index)
+        .await?
+        .is_some_and(|link| is_wireguard(&link));
+    if !is_wg {
+        return Err(std::io::Error::from_raw_os_error(libc::EOPNOTSUPP));
+    }
+
+    let mut link = LinkMessage::default();
+    link.header.index = index;
+
+    let mut message = NetlinkMessage::from(RouteNetlinkMessage::DelLink(link));
+    message.header.flags = (NLM_F_REQUEST | NLM_F_ACK) as _;
+    message.finalize();
+
+    socket_send(socket, &message).await?;
+
+    socket_recv::<RouteNetlinkMessage>(socket).await?;
+
+    Ok(())
+}
+
+/// Sets or clears `IFF_UP` on the link named `interface`.
+///
+/// Only the up flag is in the change mask, so this is a no-op for a link
+/// that is already in the requested state.
+async fn rt_set_link_up(
+    socket: &mut Async<Socket>,
+    interface: &OsStr,
+    up: bool,
+) -> std::io::Result<()> {
+    let mut link = LinkMessage::default();
+    link.header.change_mask = LinkFlags::Up;
+    if up {
+        link.header.flags = LinkFlags::Up;
+    }
+    match interface.to_str() {
+        Some(name) => link.attributes.push(LinkAttribute::IfName(name.to_owned())),
+        None => link.header.index = if_index(interface)?,
+    }
+
+    let mut message = NetlinkMessage::from(RouteNetlinkMessage::SetLink(link));
+    message.header.flags = (NLM_F_REQUEST | NLM_F_ACK) as _;
+    message.finalize();
+
+    socket_send(socket, &message).await?;
+
+    socket_recv::<RouteNetlinkMessage>(socket).await?;
+
+    Ok(())
+}
+
+/// Looks up the rtnetlink view of the wireguard device `interface`.
+///
+/// Runs on its own `NETLINK_ROUTE` socket; failures are logged and only
+/// drop the link details from the output.
+async fn wg_link(interface: &OsStr) -> Option<LinkMessage> {
+    let index = match if_index(interface) {
+        Ok(index) => index,
+        Err(err) => {
+            log::error!("failed to look up {}: {err}", interface.display());
+            return None;
+        }
+    };
+
+    match async { rt_get_link(&mut rt_socket()?, index).await }.await {
+        Ok(link) => link,
+        Err(err) => {
+            log::error!("failed to get link {index}: {err}");
+            None
+        }
+    }
+}
+
+fn link_state(link: &LinkMessage) -> &'static str {
+    let state = link.attributes.iter().find_map(|attr| match attr {
+        LinkAttribute::OperState(state) => Some(state),
+        _ => None,
+    });
+
+    match state {
+        Some(State::Up) => "up",
+        Some(State::Down) => "down",
+        Some(State::LowerLayerDown) => "lower layer down",
+        Some(State::Dormant) => "dormant",
+        Some(State::Testing) => "testing",
+        Some(State::NotPresent) => "not present",
+        // Tunnel devices such as wireguard don't track an operational state,
+        // fall back to the administrative one.
+        _ if link.header.flags.contains(LinkFlags::Up) => "up",
+        _ => "down",
+    }
+}
+
+/// Number of public key characters used as the name in `--export=hosts`.
+const HOSTS_KEY_PREFIX_LEN: usize = 8;
+
+/// Fwmark written by `--round-trip-test`.
+const ROUND_TRIP_FWMARK: u32 = 0x7767_7467;
+
+/// [Interface] keys only wg-quick(8) knows, lowercased.
+const WG_QUICK_KEYS: &[&str] = &[
+    "address",
+    "dns",
+    "mtu",
+    "table",
+    "preup",
+    "postup",
+    "predown",
+    "postdown",
+    "saveconfig",
+];
+
+/// Most bytes of peer entries in one SetDevice request. The `Peers`
+/// attribute has a 16-bit length, which a single request with a few hundred
+/// peers would already overflow.
+const SET_DEVICE_PEERS_LEN: usize = 32 * 1024;
+
+/// Interval between `GetDevice` polls in `wait`.
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+const HELP_STYLES: Styles = Styles::styled()
+    .header(AnsiColor::Yellow.on_default().effects(Effects::BOLD))
+    .usage(AnsiColor::Yellow.on_default().effects(Effects::BOLD))
+    .literal(AnsiColor::Green.on_default().effects(Effects::BOLD))
+    .placeholder(AnsiColor::Cyan.on_default())
+    .error(AnsiColor::Red.on_default().effects(Effects::BOLD))
+    .valid(AnsiColor::Green.on_default())
+    .invalid(AnsiColor::Yellow.on_default());
+
+/// Query and manage WireGuard interfaces over netlink
+///
+/// Without a command, sends a generic netlink GetDevice request for
+/// INTERFACE and prints the device and its peers. Without an INTERFACE
+/// either, does so for every wireguard link found over rtnetlink.
+#[derive(clap::Parser)]
+#[command(
+    styles = HELP_STYLES,
+    subcommand_negates_reqs = true,
+    override_usage = "wg-test [OPTIONS] [INTERFACE]\n       \
+                      wg-test [OPTIONS] --interface <INTERFACE>\n       \
+                      wg-test [OPTIONS] <COMMAND>",
+    after_long_help = "Example:\n  wg-test --with-link-info wg0\n\n\
+                       Exit status:\n  \
+                       0  success\n  \
+                       1  any other failure\n  \
+                       2  no such device\n  \
+                       3  permission denied\n  \
+                       4  the wireguard netlink family is missing\n  \
+                       5  wait timed out\n  \
+                       64 invalid command line"
+)]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Suppress normal output; errors still go to stderr and exit codes are unchanged
+    ///
+    /// Keys that a command generates or derives and prints (genkey, genpsk,
+    /// pubkey, rotate-key, set --gen-preshared-key, or any batch, for its set
+    /// lines) are still printed, since printing them is the point of the
+    /// command or they would be lost.
+    #[arg(short, long, global = true)]
+    quiet: bool,
+
+    /// Use the socket-activated netlink socket named NAME (see LISTEN_FDNAMES)
+    #[arg(long, global = true, value_name = "NAME")]
+    socket_fd_name: Option<String>,
+
+    /// Request a netlink receive buffer of BYTES (the kernel doubles it)
+    #[arg(
+        long,
+        global = true,
+        value_name = "BYTES",
+        value_parser = clap::value_parser!(i32).range(1..),
+    )]
+    rcvbuf: Option<i32>,
+
+    /// Set SO_PRIORITY on the netlink socket (values above 6 need CAP_NET_ADMIN)
+    #[arg(
+        long,
+        global = true,
+        value_name = "N",
+        value_parser = clap::value_parser!(i32).range(0..),
+    )]
+    so_priority: Option<i32>,
+
+    /// Ask the kernel to strictly validate requests (NETLINK_GET_STRICT_CHK)
+    ///
+    /// Malformed requests are then rejected with a specific error instead
+    /// of having unknown attributes or header fields silently ignored. This
+    /// helps when debugging requests, but a kernel that is stricter than
+    /// the one a request was written for may refuse it. Kernels before 4.20
+    /// don't know the option; it is skipped there with a warning.
+    #[arg(long, global = true)]
+    strict_netlink: bool,
+
+    /// Bind the netlink socket to port id N (0 lets the kernel pick one)
+    ///
+    /// Most users won't need this. It only matters when coordinating several
+    /// netlink users in one process, to avoid port id collisions.
+    #[arg(
+        long,
+        global = true,
+        value_name = "N",
+        default_value_t = 0,
+        conflicts_with = "socket_fd_name"
+    )]
+    portid: u32,
+
+    /// Print the version of the kernel's wireguard netlink family
+    #[arg(long, global = true)]
+    show_family_version: bool,
+
+    /// Set a test fwmark on INTERFACE, read it back and restore the original
+    #[arg(long, hide = true, value_name = "INTERFACE")]
+    round_trip_test: Option<OsString>,
+
+    /// Check every WireGuard interface has at least one active peer
+    ///
+    /// Prints each interface without a peer that completed a handshake in
+    /// the last --active-within, and exits with a nonzero status if
+    /// there is any.
+    #[arg(long)]
+    probe_all: bool,
+
+    #[command(flatten)]
+    show: ShowArgs,
+}
+
+#[derive(clap::Args)]
+#[command(group = clap::ArgGroup::new("json_output").args(["json", "ndjson", "msgpack"]))]
+struct ShowArgs {
+    /// WireGuard interface name; all wireguard interfaces if not given
+    interface: Option<OsString>,
+
+    /// WireGuard interface name, if not given positionally (the positional form wins)
+    #[arg(short, long = "interface", value_name = "INTERFACE")]
+    interface_flag: Option<OsString>,
+
+    /// Print a "Private key: (hidden)" line when the device has a private key
+    #[arg(long)]
+    show_private_key_presence: bool,
+
+    /// Print nothing and exit with a nonzero status if the interface has fewer than N peers
+    #[arg(long, value_name = "N")]
+    assert_peers_min: Option<usize>,
+
+    /// Exit with a nonzero status unless peer PUBKEY has endpoint IP:PORT
+    ///
+    /// May be given several times. Mismatches are printed with the actual
+    /// endpoint; the listing itself is only printed with --verbose.
+    #[arg(long, value_name = "PUBKEY=IP:PORT", value_parser = parse_endpoint_assertion)]
+    assert_endpoint: Vec<([u8; 32], std::net::SocketAddr)>,
+
+    /// Print the listing as well when asserting endpoints
+    #[arg(long)]
+    verbose: bool,
+
+    /// Also show the link MTU and state from rtnetlink
+    #[arg(long)]
+    with_link_info: bool,
+
+    /// Fail on any device or peer attribute this tool doesn't know how to display
+    #[arg(long)]
+    strict: bool,
+
+    /// Print the peers in a machine-readable format instead of the listing
+    #[arg(long, value_name = "FORMAT")]
+    export: Option<Export>,
+
+    /// Print the device and its peers as a single line of JSON instead of the listing
+    ///
+    /// Without a single interface to show, this is one line for all of them
+    /// instead, of the form {"interfaces":[...],"summary":{...}}.
+    ///
+    /// Errors then go to stderr as a JSON object too, such as
+    /// {"error":"wg0: no such device","code":"ENODEV"}. The code is one of
+    /// ENODEV, EPERM, ENOFAMILY (the wireguard family is missing),
+    /// ETIMEDOUT, ENOENT (no such peer), EINVAL (unusable input) or EOTHER.
+    #[arg(long, conflicts_with_all = ["export", "compat", "routes_script", "debug_attrs"])]
+    json: bool,
+
+    /// Print newline-delimited JSON instead of the listing
+    ///
+    /// Each interface is a line with the --json fields except for its peers,
+    /// followed by a line for each of those with the --json peer fields and
+    /// an "interface" field. Errors go to stderr as with --json.
+    #[arg(long, conflicts_with_all = ["json", "export", "compat", "routes_script", "debug_attrs"])]
+    ndjson: bool,
+
+    /// Print what --json would as MessagePack instead, with objects as maps
+    ///
+    /// The output is binary, so don't print it on a terminal. Errors go to
+    /// stderr as with --json.
+    #[arg(
+        long,
+        conflicts_with_all = ["json", "ndjson", "export", "compat", "routes_script", "debug_attrs"]
+    )]
+    msgpack: bool,
+
+    /// With --json, --ndjson or --msgpack, show an interface that doesn't exist as
+    /// {"interface":"wg0","present":false} instead of failing
+    ///
+    /// Interfaces that do exist then have "present":true. Other errors
+    /// still fail.
+    #[arg(long, requires = "json_output")]
+    missing_ok: bool,
+
+    /// Dump every received attribute in kernel order (unstable output, for bug reports)
+    ///
+    /// Private and preshared keys are printed as "(hidden)".
+    #[arg(long)]
+    debug_attrs: bool,
+
+    /// Print a peer's last handshake as "(never)", or 0 with --json, instead of omitting it
+    #[arg(long)]
+    show_zero: bool,
+
+    /// Replace public keys with short hashes and hide endpoint addresses
+    #[arg(long)]
+    anonymize: bool,
+
+    /// Print handshake ages as ISO 8601 durations (PT2M13S), also instead of times with --json
+    #[arg(long)]
+    iso8601_duration: bool,
+
+    /// Print the number of addresses covered by each peer's allowed ips
+    #[arg(long)]
+    count_addresses: bool,
+
+    /// List endpoints with the peers behind each instead of the listing
+    #[arg(long)]
+    group_by_endpoint: bool,
+
+    /// List peers grouped by handshake age, most recent first, instead of the listing
+    #[arg(long)]
+    bucket_handshakes: bool,
+
+    /// Print device flags echoed back by the kernel
+    #[arg(long)]
+    show_flags: bool,
+
+    /// Order peers instead of keeping the kernel's order
+    #[arg(long, value_name = "ORDER")]
+    sort: Option<Sort>,
+
+    /// Mimic the output format of another tool
+    #[arg(long, value_name = "TOOL")]
+    compat: Option<Compat>,
+
+    /// Only list peers with a known endpoint
+    #[arg(long)]
+    only_with_endpoint: bool,
+
+    /// Print the bytes received and sent summed over the listed peers
+    #[arg(long)]
+    total_transfer: bool,
+
+    /// Label peers from FILE, which has "PUBKEY LABEL" lines
+    #[arg(long, value_name = "FILE")]
+    labels: Option<std::path::PathBuf>,
+
+    /// Print "ip route add" commands for the allowed ips instead of the listing
+    #[arg(long, conflicts_with = "anonymize")]
+    routes_script: bool,
+
+    /// Only print the interface attributes, not its peers
+    ///
+    /// The kernel has no GetDevice variant without peers, and a dump that is
+    /// abandoned halfway keeps the socket busy, so the peers are still read;
+    /// this only saves formatting and output.
+    #[arg(long)]
+    no_peers: bool,
+
+    /// Print how many listed peers have an IPv4, IPv6 or no endpoint
+    #[arg(long)]
+    endpoint_family_summary: bool,
+
+    /// Don't end the listing of all interfaces with their total interfaces,
+    /// peers and transfer
+    #[arg(long)]
+    no_summary: bool,
+
+    /// Mark peers without a handshake in the last --active-within and exit
+    /// with a nonzero status if there is any
+    ///
+    /// The listing marks them "Peer (stale)", and --json and the formats
+    /// like it give each peer a "stale" field. The other outputs are left as
+    /// they are, but the exit status still counts.
+    #[arg(long, conflicts_with_all = ["routes_script", "debug_attrs", "assert_peers_min"])]
+    fail_on_stale: bool,
+
+    /// How recent a handshake must be for a peer to count as active
+    #[arg(
+        long,
+        visible_alias = "active-threshold",
+        value_name = "DURATION",
+        default_value = "180s",
+        value_parser = parse_duration
+    )]
+    active_within: Duration,
+
+    /// Show the output again every INTERVAL (default 2s) until interrupted
+    ///
+    /// On a terminal the screen is cleared before each refresh. Failures
+    /// are printed but don't stop the refreshes.
+    #[arg(
+        long,
+        value_name = "INTERVAL",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "2s",
+        value_parser = parse_duration
+    )]
+    watch: Option<Duration>,
+
+    /// Don't clear the screen between --watch refreshes, but print each one
+    /// after an RFC 3339 timestamp line, so the output can be appended to a log
+    #[arg(long, requires = "watch", conflicts_with_all = ["json", "ndjson", "msgpack"])]
+    no_clear: bool,
+
+    /// Wait up to MS milliseconds more, at random, before each --watch
+    /// refresh, so that hosts watching on the same interval don't all query
+    /// their kernels at once
+    #[arg(long, value_name = "MS", default_value_t = 0, requires = "watch")]
+    jitter: u64,
+
+    /// Take a baseline of each peer's handshake and transfer at the first
+    /// --watch refresh DURATION after starting, then mark peers that haven't
+    /// had a handshake or any transfer since as stalled
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration, requires = "watch")]
+    baseline_after: Option<Duration>,
+
+    /// Draw how recent each peer's handshake was over the last N --watch
+    /// refreshes, taller for fresher ones, when the output is a terminal
+    #[arg(long, value_name = "N", default_value_t = 10, requires = "watch")]
+    history: usize,
+}
+
+impl ShowArgs {
+    /// Whether the output is --json or like it, errors included.
+    fn machine_readable(&self) -> bool {
+        self.json || self.ndjson || self.msgpack
+    }
+}
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum Export {
+    /// "<first allowed ip> <public key prefix>" lines
+    Hosts,
+    /// pubkey, endpoint, allowed_ips, last_handshake, rx and tx columns
+    Csv,
+}
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum Compat {
+    /// `wg show <interface>`, without colors
+    Wg,
+}
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum Family {
+    Ipv4,
+    Ipv6,
+}
+
+impl Family {
+    fn contains(self, addr: &std::net::SocketAddr) -> bool {
+        match self {
+            Self::Ipv4 => addr.is_ipv4(),
+            Self::Ipv6 => addr.is_ipv6(),
+        }
+    }
+}
+
+impl std::fmt::Display for Family {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Ipv4 => "IPv4",
+            Self::Ipv6 => "IPv6",
+        })
+    }
+}
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum Sort {
+    /// By lowest allowed ip, then public key; peers without allowed ips last
+    AllowedIp,
+}
+
+/// How `set` and the config file commands treat questionable input.
+#[derive(Clone, Copy, clap::Args)]
+struct InputChecks {
+    /// Clamp an unclamped private key for Curve25519 instead of warning about it
+    ///
+    /// RFC 7748 clamps private keys by clearing the low three bits and
+    /// setting the second-highest one. `wg genkey` keys already are, but
+    /// keys from elsewhere may not be, and other implementations may then
+    /// derive a different public key.
+    #[arg(long)]
+    clamp: bool,
+
+    /// Reject allowed ips with host bits set instead of clearing them
+    ///
+    /// The kernel stores 10.0.0.5/24 as 10.0.0.0/24, so by default the host
+    /// bits are cleared here too, with a warning.
+    #[arg(long)]
+    strict: bool,
+}
+
+/// How genkey, genpsk and pubkey print their key.
+#[derive(Clone, Copy, clap::Args)]
+struct KeyOutput {
+    /// Print just the 44 characters of the key, without a newline
+    #[arg(long)]
+    no_newline: bool,
+
+    /// Write the key as exactly 32 raw bytes instead of base64, for tools
+    /// that read binary keys
+    #[arg(long, conflicts_with = "no_newline")]
+    binary: bool,
+}
+
+#[derive(clap::Args)]
+struct SetArgs {
+    /// WireGuard interface name
+    interface: OsString,
+
+    /// Read the new private key from FILE, as base64 like `wg genkey` prints it
+    #[arg(long, value_name = "FILE")]
+    private_key: Option<std::path::PathBuf>,
+
+    /// Set the UDP listen port (0 picks a random one)
+    #[arg(long, value_name = "PORT")]
+    listen_port: Option<u16>,
+
+    /// Set the fwmark for outgoing packets, in decimal or 0x hex ("off" or 0 clears it)
+    #[arg(long, value_name = "MARK", value_parser = parse_fwmark)]
+    fwmark: Option<u32>,
+
+    /// Set the persistent keepalive of every existing peer ("off" or 0 disables)
+    #[arg(long, value_name = "DURATION", value_parser = parse_keepalive)]
+    all_peers_keepalive: Option<u16>,
+
+    #[command(flatten)]
+    checks: InputChecks,
+
+    #[command(subcommand)]
+    peer: Option<SetPeer>,
+}
+
+impl SetArgs {
+    /// Whether nothing at all was asked for, which clap can't tell.
+    fn is_empty(&self) -> bool {
+        self.private_key.is_none()
+            && self.listen_port.is_none()
+            && self.fwmark.is_none()
+            && self.all_peers_keepalive.is_none()
+            && self.peer.is_none()
+    }
+}
+
+#[derive(clap::Subcommand)]
+enum SetPeer {
+    /// Change the configuration of a single peer
+    Peer {
+        /// Public key of the peer to change
+        public_key: String,
+
+        /// Set the endpoint, as IP:PORT or HOST:PORT
+        #[arg(long, value_name = "ENDPOINT")]
+        endpoint: Option<String>,
+
+        /// Use an address of FAMILY for a HOST:PORT endpoint, failing if it has none
+        #[arg(long, value_name = "FAMILY", requires = "endpoint")]
+        force_family: Option<Family>,
+
+        /// Make the kernel forget the endpoint, configured or roamed to
+        #[arg(long, conflicts_with = "endpoint")]
+        clear_endpoint: bool,
+
+        /// Replace the allowed ips with this comma-separated list
+        ///
+        /// "default" stands for 0.0.0.0/0 and ::/0, "default4" and "default6"
+        /// for one of them. These can't be mixed with other CIDRs.
+        #[arg(long, value_name = "CIDR", value_delimiter = ',', value_parser = parse_allowed_ip)]
+        allowed_ips: Vec<AllowedIpArg>,
+
+        /// Set the persistent keepalive ("off" or 0 disables)
+        #[arg(long, value_name = "DURATION", value_parser = parse_keepalive)]
+        persistent_keepalive: Option<u16>,
+
+        /// Remove the peer from the interface
+        #[arg(long, conflicts_with_all = ["endpoint", "clear_endpoint", "allowed_ips", "persistent_keepalive", "gen_preshared_key"])]
+        remove: bool,
+
+        /// Generate a random preshared key, set it on the peer and print it
+        #[arg(long)]
+        gen_preshared_key: bool,
+    },
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Block until a peer completes its first handshake or receives data
+    ///
+    /// Polls GetDevice on INTERFACE until the peer's last handshake time is
+    /// set, or with --rx-increase until its received byte counter grows past
+    /// the value seen on the first poll, then exits with status 0.
+    #[command(after_long_help = "Example:\n  wg-test wait wg0 --peer <PUBKEY> --timeout 30s")]
+    Wait {
+        /// WireGuard interface name
+        interface: OsString,
+
+        /// Public key of the peer to wait for
+        #[arg(long, value_name = "PUBKEY")]
+        peer: String,
+
+        /// Exit with a nonzero status if no handshake happened within DURATION
+        #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+        timeout: Option<Duration>,
+
+        /// Wait for the peer's received bytes to grow instead of a first handshake
+        #[arg(long)]
+        rx_increase: bool,
+    },
+
+    /// Print the names of all WireGuard interfaces, one per line
+    ///
+    /// Dumps all links with an rtnetlink RTM_GETLINK request and keeps those
+    /// of kind "wireguard". Prints nothing if there are none.
+    #[command(after_long_help = "Example:\n  wg-test list")]
+    List,
+
+    /// Check that generic netlink works and the wireguard family is registered
+    ///
+    /// Sends a generic netlink GetFamily request for the wireguard family and
+    /// reports the family id and version. Needs no interface.
+    #[command(after_long_help = "Example:\n  wg-test selftest")]
+    Selftest,
+
+    /// Generate a private key and print it in base64
+    ///
+    /// Like `wg genkey`: 32 bytes from the OS random source, clamped for
+    /// Curve25519.
+    #[command(after_long_help = "Example:\n  wg-test genkey | tee wg0.key | wg-test pubkey")]
+    Genkey {
+        #[command(flatten)]
+        output: KeyOutput,
+    },
+
+    /// Generate a random preshared key and print it in base64
+    ///
+    /// Like `wg genpsk`: 32 random bytes from the OS random source, without
+    /// the clamping applied to private keys.
+    #[command(after_long_help = "Example:\n  wg-test genpsk > peer.psk")]
+    Genpsk {
+        #[command(flatten)]
+        output: KeyOutput,
+    },
+
+    /// Read a base64 private key from stdin and print its public key
+    ///
+    /// Like `wg pubkey`.
+    #[command(after_long_help = "Example:\n  wg-test pubkey < wg0.key")]
+    Pubkey {
+        #[command(flatten)]
+        output: KeyOutput,
+    },
+
+    /// List the peers whose allowed ips overlap a subnet
+    ///
+    /// Sends GetDevice for INTERFACE and prints every peer with an allowed ip
+    /// overlapping CIDR. Exits with a nonzero status if there is none.
+    #[command(after_long_help = "Example:\n  wg-test peers-in wg0 10.0.0.0/16")]
+    PeersIn {
+        /// WireGuard interface name
+        interface: OsString,
+
+        /// Subnet to match, as address/prefix or a bare address
+        #[arg(value_parser = parse_cidr)]
+        cidr: (IpAddr, u8),
+    },
+
+    /// Create a WireGuard interface
+    ///
+    /// Sends an rtnetlink RTM_NEWLINK request for a link of kind wireguard
+    /// named NAME. The new interface is down and has no keys or peers.
+    #[command(after_long_help = "Example:\n  wg-test add wg0 && wg-test setconf wg0 wg0.conf")]
+    Add {
+        /// Name of the new interface
+        name: String,
+    },
+
+    /// Delete a WireGuard interface
+    ///
+    /// Sends an rtnetlink RTM_DELLINK request for INTERFACE, after checking
+    /// that it is a wireguard link.
+    #[command(after_long_help = "Example:\n  wg-test del wg0")]
+    Del {
+        /// WireGuard interface name
+        interface: OsString,
+    },
+
+    /// Bring an interface up
+    ///
+    /// Sets IFF_UP on INTERFACE with an rtnetlink RTM_SETLINK request.
+    #[command(after_long_help = "Example:\n  wg-test up wg0")]
+    Up {
+        /// Interface name
+        interface: OsString,
+    },
+
+    /// Bring an interface down
+    ///
+    /// Clears IFF_UP on INTERFACE with an rtnetlink RTM_SETLINK request.
+    #[command(after_long_help = "Example:\n  wg-test down wg0")]
+    Down {
+        /// Interface name
+        interface: OsString,
+    },
+
+    /// Replace an interface's private key with a freshly generated one
+    ///
+    /// Generates a new private key, sets it on INTERFACE with a single
+    /// SetDevice request and prints the matching public key. The interface
+    /// keeps its old key if the request fails.
+    #[command(after_long_help = "Example:\n  wg-test rotate-key wg0 --print-private-key")]
+    RotateKey {
+        /// WireGuard interface name
+        interface: OsString,
+
+        /// Also print the new private key
+        #[arg(long)]
+        print_private_key: bool,
+    },
+
+    /// Change the configuration of an interface
+    ///
+    /// Like `wg set`: the private key, listen port, fwmark and one peer are
+    /// changed with a single SetDevice request, and a peer that doesn't exist
+    /// yet is added. A generated preshared key is set in the same request,
+    /// unless it is the only change; then the peer is looked up with
+    /// GetDevice first, and left alone if missing. --all-peers-keepalive
+    /// looks up the peers and sends SetDevice requests of its own, as many as
+    /// the peers need, then prints how many peers were updated.
+    #[command(after_long_help = "Examples:\n  \
+                                 wg-test set wg0 --listen-port 51820 --private-key /etc/wg/wg0.key\n  \
+                                 wg-test set wg0 peer <PUBKEY> --endpoint vpn.example.com:51820 \\\n      \
+                                 --allowed-ips 10.0.0.2/32,fd00::2/128 --persistent-keepalive 25\n  \
+                                 wg-test set wg0 peer <PUBKEY> --remove\n  \
+                                 wg-test set wg0 --all-peers-keepalive 25\n  \
+                                 wg-test set wg0 peer <PUBKEY> --gen-preshared-key")]
+    Set(SetArgs),
+
+    /// Run set commands from a file over one netlink socket
+    ///
+    /// Like `wg-test set`, once per line of FILE: each line is a set command
+    /// as it would follow `wg-test`, split on whitespace, with no quoting.
+    /// Blank lines and lines starting with # are skipped. A line that fails
+    /// is reported with its number and the rest still run; the exit status
+    /// is that of the last failure.
+    ///
+    /// With --atomic, every line is parsed before any is run, and the first
+    /// failure stops the batch. Lines that ran before it stay applied.
+    #[command(after_long_help = "Example:\n  \
+                                 wg-test batch --atomic changes\n\n\
+                                 where changes has lines like:\n  \
+                                 # wg0 moves to a new port and drops a peer\n  \
+                                 set wg0 --listen-port 51821\n  \
+                                 set wg0 peer <PUBKEY> --remove")]
+    Batch {
+        /// File of set commands, one per line
+        file: std::path::PathBuf,
+
+        /// Check every line first, and stop at the first one that fails
+        #[arg(long)]
+        atomic: bool,
+    },
+
+    /// Print the configuration of an interface in wg(8) config format
+    ///
+    /// Like `wg showconf`: sends GetDevice for INTERFACE and prints an
+    /// [Interface] section, including the private key, and a [Peer] section
+    /// per peer, which `wg setconf` accepts back.
+    ///
+    /// To scan it into a WireGuard mobile app, pipe it into a QR encoder such
+    /// as qrencode(1). The code holds the private key, so mind who can see
+    /// the screen.
+    #[command(after_long_help = "Examples:\n  \
+                                 wg-test showconf wg0 > wg0.conf\n  \
+                                 wg-test showconf wg0 | qrencode -t ansiutf8")]
+    Showconf {
+        /// WireGuard interface name
+        interface: OsString,
+
+        /// Order the [Peer] sections instead of keeping the kernel's order
+        #[arg(long, value_name = "ORDER")]
+        sort: Option<Sort>,
+    },
+
+    /// Replace the configuration of an interface with a wg(8) config file
+    ///
+    /// Like `wg setconf`: sends SetDevice to replace all peers with the ones
+    /// in FILE and set the [Interface] values it has. Like wg(8), a long list
+    /// of peers is spread over several requests.
+    #[command(after_long_help = "Example:\n  wg-test setconf wg0 wg0.conf")]
+    Setconf {
+        /// WireGuard interface name
+        interface: OsString,
+
+        /// Config file with [Interface] and [Peer] sections, or a file:// URL of one
+        file: std::path::PathBuf,
+
+        #[command(flatten)]
+        checks: InputChecks,
+    },
+
+    /// Add the peers of a wg(8) config file to an interface
+    ///
+    /// Like `wg addconf`: peers already on INTERFACE are updated, and keep
+    /// their allowed ips with the ones from FILE appended.
+    #[command(after_long_help = "Example:\n  wg-test addconf wg0 more-peers.conf")]
+    Addconf {
+        /// WireGuard interface name
+        interface: OsString,
+
+        /// Config file with [Interface] and [Peer] sections, or a file:// URL of one
+        file: std::path::PathBuf,
+
+        #[command(flatten)]
+        checks: InputChecks,
+    },
+
+    /// Make an interface match a wg(8) config file with as few changes as possible
+    ///
+    /// Like `wg syncconf`: sends GetDevice for INTERFACE, then SetDevice to
+    /// remove the peers missing from FILE and change only what differs.
+    /// Peers left alone keep their sessions and counters.
+    #[command(after_long_help = "Example:\n  wg-test syncconf wg0 wg0.conf")]
+    Syncconf {
+        /// WireGuard interface name
+        interface: OsString,
+
+        /// Config file with [Interface] and [Peer] sections, or a file:// URL of one
+        file: std::path::PathBuf,
+
+        #[command(flatten)]
+        checks: InputChecks,
+    },
+
+    /// Check that an interface matches a wg(8) or wg-quick(8) config file
+    ///
+    /// Sends GetDevice for INTERFACE and compares it with FILE without
+    /// changing anything: the listen port, fwmark and private key FILE sets,
+    /// the peers, and their preshared keys, keepalives and allowed ips.
+    /// Endpoints aren't compared, since peers roam, and wg-quick keys such as
+    /// Address are skipped. Prints the differences, "-" lines as in FILE and
+    /// "+" lines as on the device, and exits with a nonzero status if there
+    /// are any.
+    #[command(after_long_help = "Example:\n  wg-test verify wg0 /etc/wireguard/wg0.conf")]
+    Verify {
+        /// WireGuard interface name
+        interface: OsString,
+
+        /// Config file with [Interface] and [Peer] sections, or a file:// URL of one
+        file: std::path::PathBuf,
+    },
+
+    /// Print the listen port of an interface
+    ///
+    /// Sends GetDevice for INTERFACE and prints just its listen port. Exits
+    /// with a nonzero status if the port is 0 or not reported.
+    #[command(after_long_help = "Example:\n  wg-test listen-port wg0")]
+    ListenPort {
+        /// WireGuard interface name
+        interface: OsString,
+    },
+
+    /// Re-resolve peer endpoint hostnames and update the ones that changed
+    ///
+    /// Reads "PUBKEY HOST:PORT" lines from FILE, resolves each host and
+    /// sends SetDevice for the peers of INTERFACE whose current
+    /// endpoint isn't among the resolved addresses. Prints which peers were
+    /// updated and which were unchanged.
+    #[command(after_long_help = "Example:\n  wg-test refresh-endpoints wg0 --from endpoints.txt")]
+    RefreshEndpoints {
+        /// WireGuard interface name
+        interface: OsString,
+
+        /// File of "PUBKEY HOST:PORT" lines
+        #[arg(long, value_name = "FILE")]
+        from: std::path::PathBuf,
+    },
+
+    /// Zero a peer's transfer counters by removing and re-adding it
+    ///
+    /// The kernel has no way to reset the counters, so this reads the peer's
+    /// configuration from INTERFACE and sends a single SetDevice that removes
+    /// the peer and adds it back with the same preshared key, endpoint,
+    /// allowed ips and keepalive. The peer's session is lost, which briefly
+    /// disrupts traffic until it handshakes again.
+    #[command(after_long_help = "Example:\n  wg-test reset-stats wg0 --peer <PUBKEY>")]
+    ResetStats {
+        /// WireGuard interface name
+        interface: OsString,
+
+        /// Public key of the peer to reset
+        #[arg(long, value_name = "PUBKEY")]
+        peer: String,
+    },
+}
+
+fn main() {
+    env_logger::init();
+
+    let args = <Args as clap::Parser>::try_parse().unwrap_or_else(|err| usage_error(err));
+
+    async_io::block_on(real_main(args))
+}
+
+async fn real_main(
+    Args {
+        command,
+        quiet,
+        socket_fd_name,
+        rcvbuf,
+        so_priority,
+        strict_netlink,
+        portid,
+        show_family_version,
+        round_trip_test,
+        probe_all,
+        show,
+    }: Args,
+) {
+    JSON_ERRORS.store(show.machine_readable(), Ordering::Relaxed);
+
+    let prints_keys = matches!(
+        command,
+        Some(
+            Command::Genkey { .. }
+                | Command::Genpsk { .. }
+                | Command::Pubkey { .. }
+                | Command::RotateKey { .. }
+                | Command::Set(SetArgs {
+                    peer: Some(SetPeer::Peer {
+                        gen_preshared_key: true,
+                        ..
+                    }),
+                    ..
+                })
+                // Its lines may generate preshared keys.
+                | Command::Batch { .. }
+        )
+    );
+    if quiet && !prints_keys {
+        silence_stdout().unwrap_or_else(|err| fail("failed to silence stdout", err));
+    }
+
+    if matches!(&command, Some(Command::Set(set)) if set.is_empty()) {
+        // Built first, so the subcommand's usage has the full command line.
+        let mut command = <Args as clap::CommandFactory>::command();
+        command.build();
+        let err = command
+            .find_subcommand_mut("set")
+            .expect("set is a subcommand")
+            .error(
+                clap::error::ErrorKind::MissingRequiredArgument,
+                "set needs something to change",
+            );
+        usage_error(err);
+    }
+
+    match command {
+        Some(Command::List) => return list_interfaces().await,
+        Some(Command::Genkey { output }) => return genkey(output),
+        Some(Command::Genpsk { output }) => return genpsk(output),
+        Some(Command::Pubkey { output }) => return pubkey(output),
+        Some(Command::Add { name }) => return add_link(&name).await,
+        Some(Command::Del { interface }) => return del_link(&interface).await,
+        Some(Command::Up { interface }) => return set_link_up(&interface, true).await,
+        Some(Command::Down { interface }) => return set_link_up(&interface, false).await,
+        _ => {}
+    }
+
+    let socket = wg_socket(socket_fd_name.as_deref(), portid).and_then(|socket| {
+        if let Some(size) = rcvbuf {
+            set_rcvbuf(&socket, size)?;
+        }
+        if let Some(priority) = so_priority {
+            setsockopt(&socket, libc::SOL_SOCKET, libc::SO_PRIORITY, priority).map_err(|err| {
+                std::io::Error::new(
+                    err.kind(),
+                    format!("setting SO_PRIORITY to {priority}: {err}"),
+                )
+            })?;
+        }
+        if strict_netlink {
+            match socket.set_netlink_get_strict_chk(true) {
+                Err(err) if err.raw_os_error() == Some(libc::ENOPROTOOPT) => {
+                    eprintln!("warning: strict netlink checking is not supported by this kernel")
+                }
+                ret => ret?,
+            }
+        }
+        Ok(socket)
+    });
+
+    if let Some(Command::Selftest) = command {
+        if !selftest(socket).await {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let socket = match socket {
+        Ok(socket) => socket,
+        Err(err) => fail("failed to set up the netlink socket", err),
+    };
+
+    let mut client = match WgClient::with_socket(socket).await {
+        Ok(client) => client,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            fail("wireguard", Error::FamilyNotFound)
+        }
+        Err(err) => fail("failed to look up the wireguard family", err),
+    };
+    let family = client.family();
+    if show_family_version {
+        match family.version {
+            Some(version) => println!("WireGuard netlink family version: {version}"),
+            None => println!("WireGuard netlink family version: unknown"),
+        }
+    }
+    let family_id = family.id;
+    let generic = client.socket_mut();
+
+    if let Some(interface) = round_trip_test {
+        if !self::round_trip_test(generic, family_id, &interface).await {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if probe_all {
+        if !self::probe_all(generic, family_id, show.active_within).await {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    match command {
+        Some(Command::Wait {
+            interface,
+            peer,
+            timeout,
+            rx_increase,
+        }) => wait_for_peer(generic, family_id, &interface, &peer, timeout, rx_increase).await,
+        Some(Command::PeersIn { interface, cidr }) => {
+            peers_in(generic, family_id, &interface, cidr, show.anonymize).await
+        }
+        Some(Command::RotateKey {
+            interface,
+            print_private_key,
+        }) => rotate_key(generic, family_id, &interface, print_private_key).await,
+        Some(Command::Set(set)) => {
+            if let Err(failure) = run_set(generic, family_id, &set).await {
+                failure.exit();
+            }
+        }
+        Some(Command::Batch { file, atomic }) => {
+            let status = run_batch(generic, family_id, &file, atomic).await;
+            if status != 0 {
+                std::process::exit(status);
+            }
+        }
+        Some(Command::Showconf { interface, sort }) => {
+            showconf(generic, family_id, &interface, sort).await
+        }
+        Some(Command::Setconf {
+            interface,
+            file,
+            checks,
+        }) => apply_config(generic, family_id, &interface, &file, ConfMode::Set, checks).await,
+        Some(Command::Addconf {
+            interface,
+            file,
+            checks,
+        }) => apply_config(generic, family_id, &interface, &file, ConfMode::Add, checks).await,
+        Some(Command::Syncconf {
+            interface,
+            file,
+            checks,
+        }) => {
+            apply_config(
+                generic,
+                family_id,
+                &interface,
+                &file,
+                ConfMode::Sync,
+                checks,
+            )
+            .await
+        }
+        Some(Command::Verify { interface, file }) => {
+            verify(generic, family_id, &interface, &file).await
+        }
+        Some(Command::ListenPort { interface }) => {
+            listen_port(generic, family_id, &interface).await
+        }
+        Some(Command::RefreshEndpoints { interface, from }) => {
+            refresh_endpoints(generic, family_id, &interface, &from).await
+        }
+        Some(Command::ResetStats { interface, peer }) => {
+            reset_stats(generic, family_id, &interface, &peer).await
+        }
+        Some(
+            Command::List
+            | Command::Selftest
+            | Command::Genkey { .. }
+            | Command::Genpsk { .. }
+            | Command::Pubkey { .. }
+            | Command::Add { .. }
+            | Command::Del { .. }
+            | Command::Up { .. }
+            | Command::Down { .. },
+        ) => {
+            unreachable!()
+        }
+        None => show_devices(generic, family_id, show).await,
+    }
+}
+
+async fn peers_in(
+    socket: &mut Async<Socket>,
+    family_id: u16,
+    interface: &OsStr,
+    cidr: (IpAddr, u8),
+    anonymize: bool,
+) {
+    let device = wg_device(socket, family_id, interface)
+        .await
+        .unwrap_or_else(|err| fail(interface.display(), err));
+
+    let mut found = false;
+    for peer in device.peers {
+        let ips: Vec<_> = peer
+            .allowed_ips
+            .into_iter()
+            .filter(|ip| cidr_overlaps(*ip, cidr))
+            .collect();
+        if ips.is_empty() {
+            continue;
+        }
+
+        found = true;
+        println!(
+            "{}  {}",
+            format_key(&peer.public_key, anonymize),
+            format_allowed_ips(&ips)
+        );
+    }
+
+    if !found {
+        std::process::exit(1);
+    }
+}
+
+/// Errors that end the program with a status of their own; see the exit
+/// status section of `--help`.
+#[derive(Debug, thiserror::Error)]
+enum Error {
+    #[error("netlink family not found; is the wireguard module loaded?")]
+    FamilyNotFound,
+    #[error("no such device")]
+    NoDevice,
+    #[error("{0}; this needs CAP_NET_ADMIN")]
+    PermissionDenied(std::io::Error),
+    #[error("timed out waiting for {0}")]
+    Timeout(String),
+    #[error("no peer {0}")]
+    NoPeer(String),
+    /// Input that can't be used, such as a malformed key or config file.
+    #[error("{0}")]
+    Invalid(String),
+    #[error("{0}")]
+    Other(String),
+    #[error(transparent)]
+    Io(std::io::Error),
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        // Errors wrapped with more context keep the kind, not the errno.
+        match err.raw_os_error() {
+            Some(libc::ENODEV) => Self::NoDevice,
+            _ if err.kind() == std::io::ErrorKind::PermissionDenied => Self::PermissionDenied(err),
+            _ => Self::Io(err),
+        }
+    }
+}
+
+impl Error {
+    fn exit_code(&self) -> i32 {
+        match self {
+            Self::NoPeer(_) | Self::Invalid(_) | Self::Other(_) | Self::Io(_) => 1,
+            Self::NoDevice => 2,
+            Self::PermissionDenied(_) => 3,
+            Self::FamilyNotFound => 4,
+            Self::Timeout(_) => 5,
+        }
+    }
+}
+
+/// Exit status for an invalid command line, `EX_USAGE` from sysexits(3).
+/// clap would use 2, which is taken by [`Error::NoDevice`].
+const EX_USAGE: i32 = 64;
+
+/// Prints a command line error and exits with [`EX_USAGE`]. `--help` and
+/// `--version` come through here too, and exit with status 0 as usual.
+fn usage_error(err: clap::Error) -> ! {
+    if !err.use_stderr() {
+        err.exit();
+    }
+    // Nothing sensible is left to do if stderr is gone.
+    let _ = err.print();
+    std::process::exit(EX_USAGE);
+}
+
+impl Error {
+    /// Stable name of the error for `--json`, after the errno it stands for
+    /// where there is one.
+    fn code(&self) -> &'static str {
+        match self {
+            Self::FamilyNotFound => "ENOFAMILY",
+            Self::NoDevice => "ENODEV",
+            Self::PermissionDenied(_) => "EPERM",
+            Self::Timeout(_) => "ETIMEDOUT",
+            Self::NoPeer(_) => "ENOENT",
+            Self::Invalid(_) => "EINVAL",
+            Self::Other(_) | Self::Io(_) => "EOTHER",
+        }
+    }
+}
+
+/// Whether errors go to stderr as JSON, for `--json`.
+static JSON_ERRORS: AtomicBool = AtomicBool::new(false);
+
+/// Prints `err` after `context`, or with `--json` as a JSON object with the
+/// message and [`Error::code`].
+fn print_error(context: impl std::fmt::Display, err: &Error) {
+    #[derive(serde::Serialize)]
+    struct JsonError {
+        error: String,
+        code: &'static str,
+    }
+
+    if JSON_ERRORS.load(Ordering::Relaxed) {
+        let error = JsonError {
+            error: format!("{context}: {err}"),
+            code: err.code(),
+        };
+        match serde_json::to_string(&error) {
+            Ok(error) => eprintln!("{error}"),
+            Err(_) => eprintln!("{}", error.error),
+        }
+    } else {
+        eprintln!("{context}: {err}");
+    }
+}
+
+/// Prints `err` after `context` and exits with its status.
+fn fail(context: impl std::fmt::Display, err: impl Into<Error>) -> ! {
+    let err = err.into();
+    print_error(context, &err);
+    std::process::exit(err.exit_code());
+}
+
+/// An error and what it is about, for callers that report it themselves
+/// rather than [`fail`].
+struct Failure {
+    context: String,
+    err: Error,
+}
+
+impl Failure {
+    fn new(context: impl std::fmt::Display, err: impl Into<Error>) -> Self {
+        Self {
+            context: context.to_string(),
+            err: err.into(),
+        }
+    }
+
+    /// Prints the failure and exits, like [`fail`].
+    fn exit(self) -> ! {
+        fail(self.context, self.err)
+    }
+}
+
+fn invalid_public_key(key: &str) -> Failure {
+    Failure::new(key, Error::Invalid("invalid public key".to_owned()))
+}
+
+/// Points stdout at `/dev/null` for `--quiet`.
+fn silence_stdout() -> std::io::Result<()> {
+    let null = std::fs::OpenOptions::new().write(true).open("/dev/null")?;
+    // SAFETY: both fds are open; dup2 atomically replaces stdout.
+    if unsafe { libc::dup2(null.as_raw_fd(), libc::STDOUT_FILENO) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+async fn list_interfaces() {
+    let links = async { rt_wireguard_links(&mut rt_socket()?).await }.await;
+
+    match links {
+        Ok(links) => links.iter().for_each(|name| println!("{name}")),
+        Err(err) => fail("failed to list links", err),
+    }
+}
+
+async fn rotate_key(
+    socket: &mut Async<Socket>,
+    family_id: u16,
+    interface: &OsStr,
+    print_private_key: bool,
+) {
+    let private_key =
+        wg_generate_private_key().unwrap_or_else(|err| fail("failed to generate a key", err));
+    let nlas = vec![WgDeviceAttrs::PrivateKey(private_key)];
+
+    if let Err(err) = wg_set_device(socket, family_id, interface, nlas).await {
+        fail(interface.display(), err);
+    }
+
+    if print_private_key {
+        println!("Private key: {}", wg_public_key(&private_key));
+    }
+    println!(
+        "Public key: {}",
+        wg_public_key(&x25519_public(&private_key))
+    );
+}
+
+/// The changes `set` makes with a single SetDevice request.
+struct DeviceChanges<'a> {
+    checks: InputChecks,
+    private_key: Option<&'a std::path::Path>,
+    listen_port: Option<u16>,
+    fwmark: Option<u32>,
+    peer: Option<&'a SetPeer>,
+}
+
+impl DeviceChanges<'_> {
+    /// Whether the request would carry nothing; a peer on its own is still
+    /// a change, since it adds the peer if it is missing.
+    fn is_empty(&self) -> bool {
+        let peer_changes = match self.peer {
+            Some(SetPeer::Peer {
+                endpoint,
+                clear_endpoint,
+                allowed_ips,
+                persistent_keepalive,
+                remove,
+                gen_preshared_key,
+                ..
+            }) => {
+                endpoint.is_some()
+                    || *clear_endpoint
+                    || !allowed_ips.is_empty()
+                    || persistent_keepalive.is_some()
+                    || *remove
+                    || !*gen_preshared_key
+            }
+            None => false,
+        };
+        self.private_key.is_none()
+            && self.listen_port.is_none()
+            && self.fwmark.is_none()
+            && !peer_changes
+    }
+}
+
+/// Makes the changes of a `set` command.
+async fn run_set(socket: &mut Async<Socket>, family_id: u16, set: &SetArgs) -> Result<(), Failure> {
+    let changes = DeviceChanges {
+        checks: set.checks,
+        private_key: set.private_key.as_deref(),
+        listen_port: set.listen_port,
+        fwmark: set.fwmark,
+        peer: set.peer.as_ref(),
+    };
+    if !changes.is_empty() {
+        set_device(socket, family_id, &set.interface, changes).await?;
+    } else if let Some(SetPeer::Peer {
+        public_key,
+        gen_preshared_key: true,
+        ..
+    }) = &set.peer
+    {
+        set_generated_psk(socket, family_id, &set.interface, public_key).await?;
+    }
+    if let Some(keepalive) = set.all_peers_keepalive {
+        set_all_peers_keepalive(socket, family_id, &set.interface, keepalive).await?;
+    }
+    Ok(())
+}
+
+/// The lines of a batch file with their numbers, without comments and
+/// blank lines.
+fn batch_lines(text: &str) -> impl Iterator<Item = (usize, &str)> {
+    text.lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+}
+
+/// Parses a line of a batch file as a set command.
+fn parse_batch_line(line: &str) -> Result<SetArgs, Error> {
+    let set = <Args as clap::CommandFactory>::command()
+        .find_subcommand("set")
+        .expect("set is a subcommand")
+        .clone();
+    let batch = clap::Command::new("batch")
+        .no_binary_name(true)
+        .subcommand_required(true)
+        .subcommand(set);
+
+    // Only the message, on one line, without the usage that follows it.
+    let invalid = |err: clap::Error| {
+        let err = err.render().to_string();
+        let message: Vec<_> = err
+            .lines()
+            .map(str::trim)
+            .take_while(|line| !line.is_empty())
+            .collect();
+        Error::Invalid(message.join(" ").trim_start_matches("error: ").to_owned())
+    };
+    let matches = batch
+        .try_get_matches_from(line.split_whitespace())
+        .map_err(invalid)?;
+    let Command::Set(set) =
+        <Command as clap::FromArgMatches>::from_arg_matches(&matches).map_err(invalid)?
+    else {
+        unreachable!("set is the only subcommand")
+    };
+    if set.is_empty() {
+        return Err(Error::Invalid("set needs something to change".to_owned()));
+    }
+    Ok(set)
+}
+
+/// Runs the set commands in `path`, returning the exit status of the last
+/// one that failed, or 0. With `atomic`, all lines are parsed first, and the
+/// first failure stops the rest.
+async fn run_batch(
+    socket: &mut Async<Socket>,
+    family_id: u16,
+    path: &std::path::Path,
+    atomic: bool,
+) -> i32 {
+    let text = std::fs::read_to_string(path).unwrap_or_else(|err| fail(path.display(), err));
+
+    let at = |line| format!("{}: line {line}", path.display());
+    let report = |line, failure: Failure| {
+        print_error(format!("{}: {}", at(line), failure.context), &failure.err);
+        failure.err.exit_code()
+    };
+
+    let mut parsed = Vec::new();
+    let mut status = 0;
+    for (line, text) in batch_lines(&text) {
+        match parse_batch_line(text) {
+            Ok(set) => parsed.push((line, set)),
+            Err(err) => {
+                print_error(at(line), &err);
+                if atomic {
+                    return err.exit_code();
+                }
+                status = err.exit_code();
+            }
+        }
+        // Without --atomic, each line runs before the next is parsed, so
+        // everything is reported in order.
+        if !atomic {
+            for (line, set) in parsed.drain(..) {
+                if let Err(failure) = run_set(socket, family_id, &set).await {
+                    status = report(line, failure);
+                }
+            }
+        }
+    }
+    for (line, set) in parsed {
+        if let Err(failure) = run_set(socket, family_id, &set).await {
+            return report(line, failure);
+        }
+    }
+    status
+}
+
+async fn set_device(
+    socket: &mut Async<Socket>,
+    family_id: u16,
+    interface: &OsStr,
+    changes: DeviceChanges<'_>,
+) -> Result<(), Failure> {
+    let mut nlas = Vec::new();
+    if let Some(path) = changes.private_key {
+        let key = std::fs::read_to_string(path).map_err(|err| Failure::new(path.display(), err))?;
+        let key = wg_decode_key(key.trim()).ok_or_else(|| {
+            Failure::new(
+                path.display(),
+                Error::Invalid("invalid private key".to_owned()),
+            )
+        })?;
+        let key = check_private_key(key, changes.checks, path.display());
+        nlas.push(WgDeviceAttrs::PrivateKey(key));
+    }
+    if let Some(port) = changes.listen_port {
+        nlas.push(WgDeviceAttrs::ListenPort(port));
+    }
+    if let Some(fwmark) = changes.fwmark {
+        nlas.push(WgDeviceAttrs::Fwmark(fwmark));
+    }
+
+    let mut psk = None;
+    if let Some(SetPeer::Peer {
+        public_key,
+        endpoint,
+        force_family,
+        clear_endpoint,
+        allowed_ips,
+        persistent_keepalive,
+        remove,
+        gen_preshared_key,
+    }) = changes.peer
+    {
+        let key = wg_decode_key(public_key).ok_or_else(|| invalid_public_key(public_key))?;
+
+        let mut peer = vec![WgPeerAttrs::PublicKey(key)];
+        let mut flags = 0;
+        if *remove {
+            flags |= WGPEER_F_REMOVE_ME;
+        }
+        if let Some(host) = endpoint {
+            let endpoint = resolve_endpoint(host, *force_family)
+                .map_err(|err| Failure::new("endpoint", Error::Invalid(err)))?;
+            peer.push(WgPeerAttrs::Endpoint(endpoint));
+        }
+        if *clear_endpoint {
+            // The kernel has no flag to clear an endpoint, but stores any
+            // IPv4 or IPv6 address it is given, so an unspecified one
+            // replaces the current endpoint. Nothing is sent to the peer
+            // until it reaches us and the kernel learns its address again.
+            // GetDevice then reports 0.0.0.0:0, which reads as no endpoint.
+            let unspecified = (std::net::Ipv4Addr::UNSPECIFIED, 0);
+            peer.push(WgPeerAttrs::Endpoint(unspecified.into()));
+        }
+        if let Some(keepalive) = persistent_keepalive {
+            peer.push(WgPeerAttrs::PersistentKeepalive(*keepalive));
+        }
+        if *gen_preshared_key {
+            let key = wg_generate_preshared_key()
+                .map_err(|err| Failure::new("failed to generate a key", err))?;
+            peer.push(WgPeerAttrs::PresharedKey(key));
+            psk = Some(key);
+        }
+        if !allowed_ips.is_empty() {
+            let mut allowed_ips = expand_allowed_ips(allowed_ips)
+                .map_err(|err| Failure::new("allowed-ips", Error::Invalid(err)))?;
+            check_allowed_ips(&mut allowed_ips, changes.checks, "allowed-ips")
+                .map_err(|err| Failure::new("allowed-ips", err))?;
+            flags |= WGPEER_F_REPLACE_ALLOWEDIPS;
+            peer.push(WgPeerAttrs::AllowedIps(
+                allowed_ips.into_iter().map(wg_allowed_ip).collect(),
+            ));
+        }
+        if flags != 0 {
+            peer.push(WgPeerAttrs::Flags(flags));
+        }
+        nlas.push(WgDeviceAttrs::Peers(vec![nlas::WgPeer(peer)]));
+    }
+
+    wg_set_device(socket, family_id, interface, nlas)
+        .await
+        .map_err(|err| Failure::new(interface.display(), err))?;
+    if let Some(psk) = psk {
+        print_preshared_key(&psk);
+    }
+    Ok(())
+}
+
+async fn set_all_peers_keepalive(
+    socket: &mut Async<Socket>,
+    family_id: u16,
+    interface: &OsStr,
+    keepalive: u16,
+) -> Result<(), Failure> {
+    let peers = wg_device(socket, family_id, interface)
+        .await
+        .map_err(|err| Failure::new(interface.display(), err))?
+        .peers;
+
+    if !peers.is_empty() {
+        // Update-only, so a peer removed since the dump isn't added back.
+        let peers = peers
+            .iter()
+            .map(|peer| {
+                nlas::WgPeer(vec![
+                    WgPeerAttrs::PublicKey(peer.public_key),
+                    WgPeerAttrs::Flags(WGPEER_F_UPDATE_ONLY),
+                    WgPeerAttrs::PersistentKeepalive(keepalive),
+                ])
+            })
+            .collect();
+
+        let nlas = vec![WgDeviceAttrs::Peers(peers)];
+        wg_set_device_split(socket, family_id, interface, nlas)
+            .await
+            .map_err(|err| Failure::new(interface.display(), err))?;
+    }
+
+    println!("updated {} peers", peers.len());
+    Ok(())
+}
+
+async fn showconf(
+    socket: &mut Async<Socket>,
+    family_id: u16,
+    interface: &OsStr,
+    sort: Option<Sort>,
+) {
+    let mut device = wg_device(socket, family_id, interface)
+        .await
+        .unwrap_or_else(|err| fail(interface.display(), err));
+    sort_peers(&mut device.peers, sort);
+
+    // Same keys and order as wg(8), which leaves out what isn't set.
+    println!("[Interface]");
+    if device.listen_port != 0 {
+        println!("ListenPort = {}", device.listen_port);
+    }
+    if device.fwmark != 0 {
+        println!("FwMark = 0x{:x}", device.fwmark);
+    }
+    if let Some(key) = device.private_key {
+        println!("PrivateKey = {}", wg_public_key(&key));
+    }
+
+    for peer in &device.peers {
+        println!("\n[Peer]");
+        println!("PublicKey = {}", wg_public_key(&peer.public_key));
+        if let Some(key) = peer.preshared_key {
+            println!("PresharedKey = {}", wg_public_key(&key));
+        }
+        if !peer.allowed_ips.is_empty() {
+            println!("AllowedIPs = {}", format_allowed_ips(&peer.allowed_ips));
+        }
+        if let Some(endpoint) = peer.endpoint {
+            println!("Endpoint = {endpoint}");
+        }
+        if peer.persistent_keepalive != 0 {
+            println!("PersistentKeepalive = {}", peer.persistent_keepalive);
+        }
+    }
+}
+
+/// The settings of a wg(8) config file; what the file leaves out is `None`.
+#[derive(Default)]
+struct Config {
+    private_key: Option<[u8; 32]>,
+    listen_port: Option<u16>,
+    fwmark: Option<u32>,
+    peers: Vec<PeerConfig>,
+}
+
+#[derive(Default)]
+struct PeerConfig {
+    public_key: [u8; 32],
+    preshared_key: Option<[u8; 32]>,
+    endpoint: Option<std::net::SocketAddr>,
+    allowed_ips: Vec<(IpAddr, u8)>,
+    persistent_keepalive: Option<u16>,
+}
+
+/// The most a config file may hold, far more than a config with thousands
+/// of peers needs.
+const CONFIG_SIZE_LIMIT: u64 = 1 << 20;
+
+/// Reads a config from a path or a `file://` URL of a local path, up to
+/// [`CONFIG_SIZE_LIMIT`].
+///
+/// Other URLs, such as `https://` ones, are refused: fetching them takes an
+/// HTTP and TLS client this tool doesn't have, so they are left to a
+/// download step before it.
+fn read_config(source: &std::path::Path) -> Result<String, Error> {
+    use std::io::Read as _;
+
+    let url = source.to_str().and_then(|source| source.split_once("://"));
+    let path = match url {
+        // file:///etc/wg0.conf, with an optional host of localhost.
+        Some(("file", path)) => {
+            let path = path.strip_prefix("localhost").unwrap_or(path);
+            if !path.starts_with('/') {
+                return Err(Error::Invalid(
+                    "file:// URLs must name a local path".to_owned(),
+                ));
+            }
+            std::path::Path::new(path)
+        }
+        Some((scheme, _)) => {
+            return Err(Error::Invalid(format!(
+                "{scheme}:// URLs are not supported, only paths and file:// URLs"
+            )))
+        }
+        None => source,
+    };
+
+    let mut text = String::new();
+    std::fs::File::open(path)?
+        .take(CONFIG_SIZE_LIMIT + 1)
+        .read_to_string(&mut text)?;
+    if text.len() as u64 > CONFIG_SIZE_LIMIT {
+        return Err(Error::Invalid(format!(
+            "larger than the {} MiB a config may be",
+            CONFIG_SIZE_LIMIT >> 20
+        )));
+    }
+    Ok(text)
+}
+
+/// Parses a config in the format `wg showconf` prints.
+///
+/// Keys are case-insensitive and `#` starts a comment. Endpoint hostnames
+/// are resolved here, like wg(8) does. wg-quick keys such as Address or DNS
+/// are rejected rather than silently ignored, unless `wg_quick` is set, in
+/// which case they are skipped.
+fn parse_config(text: &str, wg_quick: bool) -> Result<Config, String> {
+    #[derive(PartialEq)]
+    enum Section {
+        Interface,
+        Peer,
+    }
+
+    let key_value = |value: &str| wg_decode_key(value).ok_or_else(|| "invalid key".to_owned());
+
+    let mut config = Config::default();
+    let mut section = None;
+    // Line of the current [Peer] header, until its PublicKey turns up.
+    let mut missing_key = None;
+
+    for (i, line) in text.lines().enumerate() {
+        let n = i + 1;
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if line.starts_with('[') {
+            if let Some(line) = missing_key {
+                return Err(format!("line {line}: peer without a PublicKey"));
+            }
+            section = match line.to_ascii_lowercase().as_str() {
+                "[interface]" => Some(Section::Interface),
+                "[peer]" => {
+                    config.peers.push(PeerConfig::default());
+                    missing_key = Some(n);
+                    Some(Section::Peer)
+                }
+                _ => return Err(format!("line {n}: unknown section {line}")),
+            };
+            continue;
+        }
+
+        let Some((key, value)) = line.split_once('=') else {
+            return Err(format!("line {n}: expected KEY = VALUE"));
+        };
+        let (name, value) = (key.trim(), value.trim());
+
+        let parsed = match (&section, name.to_ascii_lowercase().as_str()) {
+            (Some(Section::Interface), "privatekey") => {
+                key_value(value).map(|key| config.private_key = Some(key))
+            }
+            (Some(Section::Interface), "listenport") => value
+                .parse()
+                .map(|port| config.listen_port = Some(port))
+                .map_err(|err| format!("{value}: {err}")),
+            (Some(Section::Interface), "fwmark") => {
+                parse_fwmark(value).map(|fwmark| config.fwmark = Some(fwmark))
+            }
+            (Some(Section::Interface), key) if wg_quick && WG_QUICK_KEYS.contains(&key) => Ok(()),
+            (Some(Section::Peer), key) => {
+                let peer = config.peers.last_mut().unwrap();
+                match key {
+                    "publickey" => key_value(value).map(|key| {
+                        peer.public_key = key;
+                        missing_key = None;
+                    }),
+                    "presharedkey" => key_value(value).map(|key| peer.preshared_key = Some(key)),
+                    "allowedips" => value
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|cidr| !cidr.is_empty())
+                        .map(parse_cidr)
+                        .collect::<Result<Vec<_>, _>>()
+                        .map(|ips| peer.allowed_ips.extend(ips)),
+                    "endpoint" => resolve_endpoint(value, None).map(|endpoint| {
+                        peer.endpoint = Some(endpoint);
+                    }),
+                    "persistentkeepalive" => parse_keepalive(value)
+                        .map(|keepalive| peer.persistent_keepalive = Some(keepalive)),
+                    _ => Err(format!("unknown key {name}")),
+                }
+            }
+            (Some(Section::Interface), _) => Err(format!("unknown key {name}")),
+            (None, _) => Err("key outside of a section".to_owned()),
+        };
+        parsed.map_err(|err| format!("line {n}: {err}"))?;
+    }
+
+    if let Some(line) = missing_key {
+        return Err(format!("line {line}: peer without a PublicKey"));
+    }
+    Ok(config)
+}
+
+/// First usable address of `host`, which is IP:PORT or HOST:PORT, or with
+/// `family` the first of that family.
+fn resolve_endpoint(host: &str, family: Option<Family>) -> Result<std::net::SocketAddr, String> {
+    use std::net::ToSocketAddrs as _;
+
+    let addrs = host
+        .to_socket_addrs()
+        .map_err(|err| format!("{host}: {err}"))?;
+    pick_endpoint(host, addrs, family)
+}
+
+/// Picks the endpoint of `host` from its resolved `addrs`, like
+/// [`resolve_endpoint`]. v4-mapped addresses count as IPv4.
+fn pick_endpoint(
+    host: &str,
+    addrs: impl IntoIterator<Item = std::net::SocketAddr>,
+    family: Option<Family>,
+) -> Result<std::net::SocketAddr, String> {
+    let mut addrs = addrs.into_iter().filter_map(wg_endpoint);
+    match family {
+        Some(family) => addrs
+            .find(|addr| family.contains(addr))
+            .ok_or_else(|| format!("{host}: no {family} addresses")),
+        None => addrs.next().ok_or_else(|| format!("{host}: no addresses")),
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum ConfMode {
+    Set,
+    Add,
+    Sync,
+}
+
+async fn apply_config(
+    socket: &mut Async<Socket>,
+    family_id: u16,
+    interface: &OsStr,
+    path: &std::path::Path,
+    mode: ConfMode,
+    checks: InputChecks,
+) {
+    let text = read_config(path)
+        .unwrap_or_else(|err| fail(format_args!("failed to read {}", path.display()), err));
+    let mut config =
+        parse_config(&text, false).unwrap_or_else(|err| fail(path.display(), Error::Invalid(err)));
+    config.private_key = config
+        .private_key
+        .map(|key| check_private_key(key, checks, path.display()));
+    for peer in &mut config.peers {
+        check_allowed_ips(&mut peer.allowed_ips, checks, path.display())
+            .unwrap_or_else(|err| fail(path.display(), err));
+    }
+
+    let live = match mode {
+        ConfMode::Sync => Some(
+            wg_device(socket, family_id, interface)
+                .await
+                .unwrap_or_else(|err| fail(interface.display(), err)),
+        ),
+        ConfMode::Set | ConfMode::Add => None,
+    };
+
+    let nlas = config_nlas(&config, mode, live.as_ref());
+    if nlas.is_empty() {
+        log::info!("{} already matches {}", interface.display(), path.display());
+        return;
+    }
+    if let Err(err) = wg_set_device_split(socket, family_id, interface, nlas).await {
+        fail(interface.display(), err);
+    }
+}
+
+/// The SetDevice attributes applying `config`. For syncconf, `live` is the
+/// current device, and values it already has are left out.
+fn config_nlas(config: &Config, mode: ConfMode, live: Option<&WgDevice>) -> Vec<WgDeviceAttrs> {
+    let mut nlas = Vec::new();
+
+    if let Some(key) = config.private_key {
+        if live.is_none_or(|live| live.private_key != Some(key)) {
+            nlas.push(WgDeviceAttrs::PrivateKey(key));
+        }
+    }
+    if let Some(port) = config.listen_port {
+        if live.is_none_or(|live| live.listen_port != port) {
+            nlas.push(WgDeviceAttrs::ListenPort(port));
+        }
+    }
+    if let Some(fwmark) = config.fwmark {
+        if live.is_none_or(|live| live.fwmark != fwmark) {
+            nlas.push(WgDeviceAttrs::Fwmark(fwmark));
+        }
+    }
+    if mode == ConfMode::Set {
+        nlas.push(WgDeviceAttrs::Flags(WGDEVICE_F_REPLACE_PEERS));
+    }
+
+    let mut peers = Vec::new();
+    for peer in &config.peers {
+        let current = live.and_then(|live| live.peer(&peer.public_key));
+        peers.extend(peer_config_nlas(peer, mode, current));
+    }
+    if let Some(live) = live {
+        for current in &live.peers {
+            if !config
+                .peers
+                .iter()
+                .any(|peer| peer.public_key == current.public_key)
+            {
+                peers.push(nlas::WgPeer(vec![
+                    WgPeerAttrs::PublicKey(current.public_key),
+                    WgPeerAttrs::Flags(WGPEER_F_REMOVE_ME),
+                ]));
+            }
+        }
+    }
+    if !peers.is_empty() {
+        nlas.push(WgDeviceAttrs::Peers(peers));
+    }
+
+    nlas
+}
+
+/// The peer entry applying `peer`, or `None` if `current`, its live state
+/// for syncconf, already matches.
+fn peer_config_nlas(
+    peer: &PeerConfig,
+    mode: ConfMode,
+    current: Option<&WgPeer>,
+) -> Option<nlas::WgPeer> {
+    let mut nlas = vec![WgPeerAttrs::PublicKey(peer.public_key)];
+    let allowed_ips = || {
+        let ips = peer
+            .allowed_ips
+            .iter()
+            .map(|&ip| wg_allowed_ip(ip))
+            .collect();
+        WgPeerAttrs::AllowedIps(ips)
+    };
+
+    let Some(current) = current else {
+        if let Some(key) = peer.preshared_key {
+            nlas.push(WgPeerAttrs::PresharedKey(key));
+        }
+        if let Some(endpoint) = peer.endpoint {
+            nlas.push(WgPeerAttrs::Endpoint(endpoint));
+        }
+        if let Some(keepalive) = peer.persistent_keepalive {
+            nlas.push(WgPeerAttrs::PersistentKeepalive(keepalive));
+        }
+        // addconf appends to the allowed ips of a peer that already exists.
+        if mode != ConfMode::Add {
+            nlas.push(WgPeerAttrs::Flags(WGPEER_F_REPLACE_ALLOWEDIPS));
+        }
+        nlas.push(allowed_ips());
+        return Some(nlas::WgPeer(nlas));
+    };
+
+    // What the file leaves out is cleared, except the endpoint, which the
+    // peer may have roamed to.
+    let preshared_key = peer.preshared_key.unwrap_or([0; 32]);
+    if current.preshared_key.unwrap_or([0; 32]) != preshared_key {
+        nlas.push(WgPeerAttrs::PresharedKey(preshared_key));
+    }
+    if let Some(endpoint) = peer.endpoint.filter(|&ep| current.endpoint != Some(ep)) {
+        nlas.push(WgPeerAttrs::Endpoint(endpoint));
+    }
+    let keepalive = peer.persistent_keepalive.unwrap_or(0);
+    if current.persistent_keepalive != keepalive {
+        nlas.push(WgPeerAttrs::PersistentKeepalive(keepalive));
+    }
+    let sorted = |ips: &[(IpAddr, u8)]| {
+        let mut ips = ips.to_vec();
+        ips.sort();
+        ips.dedup();
+        ips
+    };
+    if sorted(&current.allowed_ips) != sorted(&peer.allowed_ips) {
+        nlas.push(WgPeerAttrs::Flags(WGPEER_F_REPLACE_ALLOWEDIPS));
+        nlas.push(allowed_ips());
+    }
+
+    (nlas.len() > 1).then_some(nlas::WgPeer(nlas))
+}
+
+async fn verify(
+    socket: &mut Async<Socket>,
+    family_id: u16,
+    interface: &OsStr,
+    path: &std::path::Path,
+) {
+    let text = read_config(path)
+        .unwrap_or_else(|err| fail(format_args!("failed to read {}", path.display()), err));
+    let config =
+        parse_config(&text, true).unwrap_or_else(|err| fail(path.display(), Error::Invalid(err)));
+    let live = wg_device(socket, family_id, interface)
+        .await
+        .unwrap_or_else(|err| fail(interface.display(), err));
+
+    let diff = config_diff(&config, &live);
+    if diff.is_empty() {
+        log::info!("{} matches {}", interface.display(), path.display());
+        return;
+    }
+    for line in diff {
+        println!("{line}");
+    }
+    std::process::exit(1);
+}
+
+/// The lines in which `live` differs from `config`, in config file format.
+///
+/// GetDevice needs CAP_NET_ADMIN, and the kernel then reports the private
+/// and preshared keys too, so they are compared by value; they are only
+/// printed as "(hidden)" or "(none)".
+fn config_diff(config: &Config, live: &WgDevice) -> Vec<String> {
+    let secret = |key: Option<[u8; 32]>| match key {
+        Some(_) => "(hidden)",
+        None => "(none)",
+    };
+    let keepalive = |keepalive: u16| match keepalive {
+        0 => "off".to_owned(),
+        secs => secs.to_string(),
+    };
+    let sorted = |ips: &[(IpAddr, u8)]| {
+        let mut ips = ips.to_vec();
+        ips.sort();
+        ips.dedup();
+        ips
+    };
+
+    let mut interface = Vec::new();
+    if let Some(port) = config.listen_port.filter(|&port| port != live.listen_port) {
+        interface.push(format!("-ListenPort = {port}"));
+        interface.push(format!("+ListenPort = {}", live.listen_port));
+    }
+    if let Some(fwmark) = config.fwmark.filter(|&fwmark| fwmark != live.fwmark) {
+        interface.push(format!("-FwMark = 0x{fwmark:x}"));
+        interface.push(format!("+FwMark = 0x{:x}", live.fwmark));
+    }
+    if let Some(key) = config
+        .private_key
+        .filter(|&key| live.private_key != Some(key))
+    {
+        interface.push(format!("-PrivateKey = {}", secret(Some(key))));
+        interface.push(format!("+PrivateKey = {}", secret(live.private_key)));
+    }
+
+    let mut diff = Vec::new();
+    if !interface.is_empty() {
+        diff.push("[Interface]".to_owned());
+        diff.extend(interface);
+    }
+
+    for peer in &config.peers {
+        let key = wg_public_key(&peer.public_key);
+        let Some(current) = live.peer(&peer.public_key) else {
+            diff.push(String::new());
+            diff.push("-[Peer]".to_owned());
+            diff.push(format!("-PublicKey = {key}"));
+            continue;
+        };
+
+        let mut lines = Vec::new();
+        if peer.preshared_key != current.preshared_key {
+            lines.push(format!("-PresharedKey = {}", secret(peer.preshared_key)));
+            lines.push(format!("+PresharedKey = {}", secret(current.preshared_key)));
+        }
+        let wanted = peer.persistent_keepalive.unwrap_or(0);
+        if wanted != current.persistent_keepalive {
+            lines.push(format!("-PersistentKeepalive = {}", keepalive(wanted)));
+            let current = keepalive(current.persistent_keepalive);
+            lines.push(format!("+PersistentKeepalive = {current}"));
+        }
+        let (wanted, current) = (sorted(&peer.allowed_ips), sorted(&current.allowed_ips));
+        if wanted != current {
+            lines.push(format!("-AllowedIPs = {}", format_allowed_ips(&wanted)));
+            lines.push(format!("+AllowedIPs = {}", format_allowed_ips(&current)));
+        }
+
+        if !lines.is_empty() {
+            diff.push(String::new());
+            diff.push("[Peer]".to_owned());
+            diff.push(format!("PublicKey = {key}"));
+            diff.extend(lines);
+        }
+    }
+
+    for current in &live.peers {
+        if !config
+            .peers
+            .iter()
+            .any(|peer| peer.public_key == current.public_key)
+        {
+            diff.push(String::new());
+            diff.push("+[Peer]".to_owned());
+            diff.push(format!(
+                "+PublicKey = {}",
+                wg_public_key(&current.public_key)
+            ));
+        }
+    }
+
+    // Sections are separated by blank lines, not started with one.
+    if diff.first().is_some_and(String::is_empty) {
+        diff.remove(0);
+    }
+    diff
+}
+
+async fn listen_port(socket: &mut Async<Socket>, family_id: u16, interface: &OsStr) {
+    let device = wg_device(socket, family_id, interface)
+        .await
+        .unwrap_or_else(|err| fail(interface.display(), err));
+
+    match device.listen_port {
+        0 => fail(
+            interface.display(),
+            Error::Other("no listen port".to_owned()),
+        ),
+        port => println!("{port}"),
+    }
+}
+
+async fn refresh_endpoints(
+    socket: &mut Async<Socket>,
+    family_id: u16,
+    interface: &OsStr,
+    path: &std::path::Path,
+) {
+    use std::net::ToSocketAddrs as _;
+
+    let hosts = read_key_file(path).unwrap_or_else(|err| fail(path.display(), err));
+    let device = wg_device(socket, family_id, interface)
+        .await
+        .unwrap_or_else(|err| fail(interface.display(), err));
+
+    let mut failed = false;
+    let mut updates = Vec::new();
+    for (peer, host) in hosts {
+        let Some(current) = device.peer_by_base64(&peer) else {
+            eprintln!("{}: no peer {peer}", interface.display());
+            failed = true;
+            continue;
+        };
+
+        let resolved: Vec<_> = match host.to_socket_addrs() {
+            Ok(addrs) => addrs.filter_map(wg_endpoint).collect(),
+            Err(err) => {
+                eprintln!("{host}: {err}");
+                failed = true;
+                continue;
+            }
+        };
+        let Some(&endpoint) = resolved.first() else {
+            eprintln!("{host}: no addresses");
+            failed = true;
+            continue;
+        };
+
+        match current.endpoint {
+            Some(old) if resolved.contains(&old) => println!("unchanged: {peer} {old}"),
+            old => {
+                let old = old.map_or("(none)".to_owned(), |old| old.to_string());
+                updates.push((current.public_key, endpoint, format!("{peer} {old}")));
+            }
+        }
+    }
+
+    if !updates.is_empty() {
+        // Update-only, so a peer removed meanwhile isn't added back.
+        let peers = updates
+            .iter()
+            .map(|&(key, endpoint, _)| {
+                nlas::WgPeer(vec![
+                    WgPeerAttrs::PublicKey(key),
+                    WgPeerAttrs::Flags(WGPEER_F_UPDATE_ONLY),
+                    WgPeerAttrs::Endpoint(endpoint),
+                ])
+            })
+            .collect();
+        let nlas = vec![WgDeviceAttrs::Peers(peers)];
+        if let Err(err) = wg_set_device_split(socket, family_id, interface, nlas).await {
+            fail(interface.display(), err);
+        }
+    }
+    for (_, endpoint, old) in updates {
+        println!("updated: {old} -> {endpoint}");
+    }
+
+    if failed {
+        std::process::exit(1);
+    }
+}
+
+async fn reset_stats(socket: &mut Async<Socket>, family_id: u16, interface: &OsStr, peer: &str) {
+    let key = wg_decode_key(peer).unwrap_or_else(|| invalid_public_key(peer).exit());
+
+    let device = wg_device(socket, family_id, interface)
+        .await
+        .unwrap_or_else(|err| fail(interface.display(), err));
+    let Some(current) = device.peer(&key) else {
+        fail(interface.display(), Error::NoPeer(peer.to_owned()));
+    };
+
+    let mut config = vec![WgPeerAttrs::PublicKey(key)];
+    if let Some(psk) = current.preshared_key {
+        config.push(WgPeerAttrs::PresharedKey(psk));
+    }
+    if let Some(endpoint) = current.endpoint {
+        config.push(WgPeerAttrs::Endpoint(endpoint));
+    }
+    config.push(WgPeerAttrs::PersistentKeepalive(
+        current.persistent_keepalive,
+    ));
+    config.push(WgPeerAttrs::AllowedIps(
+        current
+            .allowed_ips
+            .iter()
+            .map(|&ip| wg_allowed_ip(ip))
+            .collect(),
+    ));
+
+    eprintln!("warning: {peer} is removed and re-added, its traffic is briefly disrupted");
+
+    let remove = nlas::WgPeer(vec![
+        WgPeerAttrs::PublicKey(key),
+        WgPeerAttrs::Flags(WGPEER_F_REMOVE_ME),
+    ]);
+    let nlas = vec![WgDeviceAttrs::Peers(vec![remove, nlas::WgPeer(config)])];
+    if let Err(err) = wg_set_device(socket, family_id, interface, nlas).await {
+        fail(interface.display(), err);
+    }
+}
+
+async fn set_generated_psk(
+    socket: &mut Async<Socket>,
+    family_id: u16,
+    interface: &OsStr,
+    peer: &str,
+) -> Result<(), Failure> {
+    let key = wg_decode_key(peer).ok_or_else(|| invalid_public_key(peer))?;
+
+    // An update-only SetDevice silently ignores unknown peers, so check first
+    // rather than print a key that was never applied.
+    match wg_device(socket, family_id, interface).await {
+        Ok(device) if device.peer(&key).is_some() => {}
+        Ok(_) => {
+            return Err(Failure::new(
+                interface.display(),
+                Error::NoPeer(peer.to_owned()),
+            ))
+        }
+        Err(err) => return Err(Failure::new(interface.display(), err)),
+    }
+
+    let psk =
+        wg_generate_preshared_key().map_err(|err| Failure::new("failed to generate a key", err))?;
+
+    let peer = nlas::WgPeer(vec![
+        WgPeerAttrs::PublicKey(key),
+        WgPeerAttrs::Flags(WGPEER_F_UPDATE_ONLY),
+        WgPeerAttrs::PresharedKey(psk),
+    ]);
+    if let Err(err) = wg_set_device(
+        socket,
+        family_id,
+        interface,
+        vec![WgDeviceAttrs::Peers(vec![peer])],
+    )
+    .await
+    {
+        return Err(Failure::new(interface.display(), err));
+    }
+
+    print_preshared_key(&psk);
+    Ok(())
+}
+
+/// Prints a preshared key that was just set, warning if it goes to a
+/// terminal.
+fn print_preshared_key(psk: &[u8; 32]) {
+    if std::io::stdout().is_terminal() {
+        eprintln!("warning: printing a secret preshared key to the terminal");
+    }
+    println!("{}", wg_public_key(psk));
+}
+
+/// Prints a key from genkey, genpsk or pubkey.
+fn print_key(key: &[u8; 32], output: KeyOutput) {
+    if output.binary {
+        use std::io::Write as _;
+
+        let mut stdout = std::io::stdout().lock();
+        if let Err(err) = stdout.write_all(key).and_then(|()| stdout.flush()) {
+            fail("failed to write the key", err);
+        }
+    } else if output.no_newline {
+        print!("{}", wg_public_key(key));
+    } else {
+        println!("{}", wg_public_key(key));
+    }
+}
+
+fn genkey(output: KeyOutput) {
+    let key = wg_generate_private_key().unwrap_or_else(|err| fail("failed to generate a key", err));
+    print_key(&key, output);
+}
+
+fn pubkey(output: KeyOutput) {
+    let mut key = String::new();
+    if let Err(err) = std::io::Read::read_to_string(&mut std::io::stdin(), &mut key) {
+        fail("failed to read stdin", err);
+    }
+    let key = wg_decode_key(key.trim())
+        .unwrap_or_else(|| fail("stdin", Error::Invalid("invalid private key".to_owned())));
+    print_key(&x25519_public(&key), output);
+}
+
+fn genpsk(output: KeyOutput) {
+    let psk =
+        wg_generate_preshared_key().unwrap_or_else(|err| fail("failed to generate a key", err));
+    print_key(&psk, output);
+}
+
+async fn add_link(name: &str) {
+    let ret = async { rt_add_wireguard_link(&mut rt_socket()?, name).await }.await;
+    match ret {
+        Ok(()) => {}
+        Err(err) if err.raw_os_error() == Some(libc::EOPNOTSUPP) => fail(
+            name,
+            Error::Other(
+                "wireguard links are not supported; is the wireguard module loaded?".to_owned(),
+            ),
+        ),
+        Err(err) => fail(name, err),
+    }
+}
+
+async fn del_link(interface: &OsStr) {
+    let ret = async { rt_del_wireguard_link(&mut rt_socket()?, interface).await }.await;
+    match ret {
+        Ok(()) => {}
+        Err(err) if err.raw_os_error() == Some(libc::EOPNOTSUPP) => fail(
+            interface.display(),
+            Error::Other("not a wireguard interface".to_owned()),
+        ),
+        Err(err) => fail(interface.display(), err),
+    }
+}
+
+async fn set_link_up(interface: &OsStr, up: bool) {
+    let ret = async { rt_set_link_up(&mut rt_socket()?, interface, up).await }.await;
+
+    match ret {
+        Ok(()) => {}
+        Err(err) => fail(interface.display(), err),
+    }
+}
+
+async fn wg_fwmark(
+    socket: &mut Async<Socket>,
+    family_id: u16,
+    interface: &OsStr,
+) -> std::io::Result<u32> {
+    Ok(wg_device(socket, family_id, interface).await?.fwmark)
+}
+
+/// Exercises `GetDevice` and `SetDevice` end to end through the fwmark.
+///
+/// The original fwmark is restored even when the check fails.
+async fn round_trip_test(socket: &mut Async<Socket>, family_id: u16, interface: &OsStr) -> bool {
+    let original = match wg_fwmark(socket, family_id, interface).await {
+        Ok(fwmark) => fwmark,
+        Err(err) => {
+            println!(
+                "fail: round-trip on {}: reading fwmark: {err}",
+                interface.display()
+            );
+            return false;
+        }
+    };
+
+    let test = if original == ROUND_TRIP_FWMARK {
+        ROUND_TRIP_FWMARK + 1
+    } else {
+        ROUND_TRIP_FWMARK
+    };
+
+    let result = match wg_set_device(
+        socket,
+        family_id,
+        interface,
+        vec![WgDeviceAttrs::Fwmark(test)],
+    )
+    .await
+    {
+        Ok(()) => wg_fwmark(socket, family_id, interface).await,
+        Err(err) => Err(err),
+    };
+
+    let restored = wg_set_device(
+        socket,
+        family_id,
+        interface,
+        vec![WgDeviceAttrs::Fwmark(original)],
+    )
+    .await;
+
+    let passed = match result {
+        Ok(fwmark) if fwmark == test => {
+            println!("pass: round-trip on {}", interface.display());
+            true
+        }
+        Ok(fwmark) => {
+            println!(
+                "fail: round-trip on {}: set fwmark {test}, read back {fwmark}",
+                interface.display()
+            );
+            false
+        }
+        Err(err) => {
+            println!("fail: round-trip on {}: {err}", interface.display());
+            false
+        }
+    };
+
+    if let Err(err) = restored {
+        println!(
+            "fail: round-trip on {}: restoring fwmark {original}: {err}",
+            interface.display()
+        );
+        return false;
+    }
+
+    passed
+}
+
+/// Checks that every WireGuard interface has a peer whose last handshake is
+/// at most `threshold` old, printing the interfaces that don't.
+async fn probe_all(socket: &mut Async<Socket>, family_id: u16, threshold: Duration) -> bool {
+    let interfaces = match async { rt_wireguard_links(&mut rt_socket()?).await }.await {
+        Ok(interfaces) => interfaces,
+        Err(err) => fail("failed to list links", err),
+    };
+
+    let now = SystemTime::now();
+    let is_active = |peer: &WgPeer| {
+        peer.last_handshake
+            .is_some_and(|ts| now.duration_since(ts).unwrap_or_default() <= threshold)
+    };
+
+    let mut healthy = true;
+    for interface in interfaces {
+        match wg_device(socket, family_id, interface.as_ref()).await {
+            Ok(device) if device.peers.iter().any(is_active) => {}
+            Ok(_) => {
+                println!("{interface}: no active peers");
+                healthy = false;
+            }
+            Err(err) => {
+                println!("{interface}: {err}");
+                healthy = false;
+            }
+        }
+    }
+
+    healthy
+}
+
+/// Checks that generic netlink works and the wireguard family is registered.
+async fn selftest(socket: std::io::Result<Socket>) -> bool {
+    let mut socket = match socket.and_then(Async::new) {
+        Ok(socket) => {
+            println!("pass: generic netlink socket");
+            socket
+        }
+        Err(err) => {
+            println!("fail: generic netlink socket: {err}");
+            return false;
+        }
+    };
+
+    let family = match wg_family(&mut socket).await {
+        Ok(Some(family)) => family,
+        Ok(None) => {
+            println!("fail: wireguard family registered: no family id in reply");
+            return false;
+        }
+        Err(err) if err.raw_os_error() == Some(libc::ENOENT) => {
+            println!("fail: wireguard family registered: no such family");
+            return false;
+        }
+        Err(err) => {
+            println!("fail: wireguard family registered: {err}");
+            return false;
+        }
+    };
+
+    println!("pass: wireguard family registered");
+    println!("      family id: {}", family.id);
+    if let Some(version) = family.version {
+        println!("      version: {version}");
+    }
+
+    true
+}
+
+async fn wait_for_peer(
+    socket: &mut Async<Socket>,
+    family_id: u16,
+    interface: &OsStr,
+    peer: &str,
+    timeout: Option<Duration>,
+    rx_increase: bool,
+) {
+    let key = wg_decode_key(peer).unwrap_or_else(|| invalid_public_key(peer).exit());
+
+    let started = Instant::now();
+    let mut rx_baseline = None;
+
+    loop {
+        let device = wg_device(socket, family_id, interface)
+            .await
+            .unwrap_or_else(|err| fail(interface.display(), err));
+
+        let Some(found) = device
+            .peers
+            .into_iter()
+            .find(|found| found.public_key == key)
+        else {
+            fail(interface.display(), Error::NoPeer(peer.to_owned()));
+        };
+
+        let done = if rx_increase {
+            found.rx_bytes > *rx_baseline.get_or_insert(found.rx_bytes)
+        } else {
+            found.last_handshake.is_some()
+        };
+        if done {
+            return;
+        }
+
+        if timeout.is_some_and(|timeout| started.elapsed() >= timeout) {
+            let what = if rx_increase {
+                "received data"
+            } else {
+                "a handshake"
+            };
+            fail(
+                interface.display(),
+                Error::Timeout(format!("{what} from {peer}")),
+            );
+        }
+
+        async_io::Timer::after(WAIT_POLL_INTERVAL).await;
+    }
+}
+
+/// Describes how long before `now` the handshake at `ts` happened, or with
+/// `iso8601` gives just the bare duration, such as `PT2M13S`.
+///
+/// A handshake in the future, which happens when the clock is stepped back,
+/// is clamped to zero and reads as "just now", or `PT0S`.
+fn format_handshake_age(now: SystemTime, ts: SystemTime, iso8601: bool) -> String {
+    let elapsed = now.duration_since(ts).map(|elapsed| elapsed.as_secs());
+    match elapsed {
+        _ if iso8601 => format_iso8601_duration(elapsed.unwrap_or(0)),
+        Ok(elapsed) => format!("{elapsed}s ago"),
+        Err(_) => "just now".to_owned(),
+    }
+}
+
+/// Formats `secs` as an ISO 8601 duration, e.g. `PT1H2M13S`.
+fn format_iso8601_duration(secs: u64) -> String {
+    let (hours, minutes, secs) = (secs / 3600, secs / 60 % 60, secs % 60);
+
+    let mut duration = String::from("PT");
+    if hours != 0 {
+        duration += &format!("{hours}H");
+    }
+    if minutes != 0 {
+        duration += &format!("{minutes}M");
+    }
+    if secs != 0 || duration.len() == 2 {
+        duration += &format!("{secs}S");
+    }
+    duration
+}
+
+/// Names the bits set in a device `Flags` attribute, unknown ones in hex.
+fn device_flag_names(flags: u32) -> Vec<String> {
+    const NAMES: &[(u32, &str)] = &[(WGDEVICE_F_REPLACE_PEERS, "REPLACE_PEERS")];
+
+    let mut names: Vec<String> = NAMES
+        .iter()
+        .filter(|(flag, _)| flags & flag != 0)
+        .map(|(_, name)| name.to_string())
+        .collect();
+
+    let unknown = NAMES.iter().fold(flags, |flags, (flag, _)| flags & !flag);
+    if unknown != 0 {
+        names.push(format!("{unknown:#x}"));
+    }
+    if names.is_empty() {
+        names.push("(none)".to_owned());
+    }
+    names
+}
+
+/// Reports an attribute the display code skips, which is fatal with `--strict`.
+fn unhandled_attr(strict: bool, kind: &str, nla: &impl std::fmt::Debug) {
+    let nla = format!("{nla:?}");
+    if strict {
+        let name = nla.split('(').next().unwrap_or(&nla);
+        fail(
+            format!("unhandled {kind} attribute"),
+            Error::Other(name.to_owned()),
+        );
+    }
+    log::debug!("skipping {kind} attribute {nla}");
+}
+
+/// A random delay of up to `max_ms` milliseconds, or none if the OS random
+/// source fails.
+fn jitter(max_ms: u64) -> Duration {
+    let mut random = [0; 8];
+    match getrandom::getrandom(&mut random) {
+        Ok(()) => Duration::from_millis(u64::from_ne_bytes(random) % max_ms.saturating_add(1)),
+        Err(_) => Duration::ZERO,
+    }
+}
+
+/// What --watch remembers between refreshes about each peer, by interface
+/// and public key.
+#[derive(Default)]
+struct WatchState {
+    peers: HashMap<(OsString, [u8; 32]), PeerSamples>,
+    /// Whether it is time for --baseline-after, so peers without a baseline
+    /// get one
+    baseline_due: bool,
+    /// How many handshake ages to keep for --history, 0 for none
+    history: usize,
+    /// The handshake age that draws as the lowest bar
+    active_within: Duration,
+}
+
+/// A peer as the earlier --watch refreshes saw it.
+struct PeerSamples {
+    endpoint: Option<std::net::SocketAddr>,
+    baseline: Option<PeerProgress>,
+    /// Handshake ages, oldest first
+    ages: VecDeque<Option<Duration>>,
+}
+
+/// What a peer that is still alive keeps moving on.
+#[derive(PartialEq)]
+struct PeerProgress {
+    last_handshake: Option<SystemTime>,
+    rx_bytes: u64,
+    tx_bytes: u64,
+}
+
+impl PeerProgress {
+    fn of(peer: &WgPeer) -> Self {
+        PeerProgress {
+            last_handshake: peer.last_handshake,
+            rx_bytes: peer.rx_bytes,
+            tx_bytes: peer.tx_bytes,
+        }
+    }
+}
+
+/// What is worth pointing out about a peer since the last --watch refresh.
+#[derive(Default)]
+struct PeerNotes {
+    /// The endpoint the peer had before, if it changed
+    roamed_from: Option<Option<std::net::SocketAddr>>,
+    /// Whether the peer hasn't moved on from its baseline
+    stalled: bool,
+    /// The handshake ages drawn for --history
+    sparkline: Option<String>,
+}
+
+impl WatchState {
+    /// Records this refresh's view of `peer` on `interface` at `now`. Only
+    /// the sparkline is noted the first time a peer is seen.
+    fn sample(&mut self, interface: &OsStr, peer: &WgPeer, now: SystemTime) -> PeerNotes {
+        let mut notes = PeerNotes::default();
+        let samples = self
+            .peers
+            .entry((interface.to_owned(), peer.public_key))
+            .or_insert_with(|| PeerSamples {
+                endpoint: peer.endpoint,
+                baseline: None,
+                ages: VecDeque::new(),
+            });
+
+        if samples.endpoint != peer.endpoint {
+            notes.roamed_from = Some(std::mem::replace(&mut samples.endpoint, peer.endpoint));
+        }
+        match &samples.baseline {
+            Some(baseline) => notes.stalled = *baseline == PeerProgress::of(peer),
+            None if self.baseline_due => samples.baseline = Some(PeerProgress::of(peer)),
+            None => {}
+        }
+        if self.history != 0 {
+            let age = peer
+                .last_handshake
+                .map(|ts| now.duration_since(ts).unwrap_or_default());
+            samples.ages.push_back(age);
+            while samples.ages.len() > self.history {
+                samples.ages.pop_front();
+            }
+            notes.sparkline = Some(sparkline(&samples.ages, self.active_within));
+        }
+        notes
+    }
+}
+
+/// Draws handshake ages as bars, full for a handshake just now down to the
+/// lowest for one `oldest` or longer ago, and blank for none.
+fn sparkline(ages: &VecDeque<Option<Duration>>, oldest: Duration) -> String {
+    const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+    let oldest = oldest.as_millis().max(1);
+    ages.iter()
+        .map(|age| match age {
+            Some(age) => {
+                let staleness = (age.as_millis() * 8 / oldest).min(7) as usize;
+                BARS[7 - staleness]
+            }
+            None => ' ',
+        })
+        .collect()
+}
+
+/// Shows the interface from `args`, each interface named on stdin if it is
+/// `-`, or every wireguard link if there is none. With --watch, does so
+/// again every interval until interrupted.
+async fn show_devices(socket: &mut Async<Socket>, family_id: u16, args: ShowArgs) {
+    let Some(interval) = args.watch else {
+        let interfaces = interface_names(&args).await;
+        let status = show_each(socket, family_id, interfaces, &args, None).await;
+        if status != 0 {
+            std::process::exit(status);
+        }
+        return;
+    };
+
+    // Names on stdin can only be read once; the links are listed again on
+    // every refresh so new interfaces show up.
+    let interface = args.interface.as_ref().or(args.interface_flag.as_ref());
+    let stdin_names: Option<Vec<_>> = match interface {
+        Some(interface) if interface == "-" => Some(interface_names(&args).await.collect()),
+        _ => None,
+    };
+
+    let terminal = std::io::stdout().is_terminal();
+    let mut state = WatchState {
+        history: if terminal { args.history } else { 0 },
+        active_within: args.active_within,
+        ..Default::default()
+    };
+    let started = Instant::now();
+    for refresh in 0.. {
+        state.baseline_due = args
+            .baseline_after
+            .is_some_and(|after| started.elapsed() >= after);
+        if args.no_clear {
+            if refresh != 0 {
+                println!();
+            }
+            println!("{}", format_rfc3339(SystemTime::now()));
+        } else if terminal {
+            // Home the cursor and clear the screen.
+            print!("\x1b[H\x1b[2J");
+        } else if refresh != 0 && !args.machine_readable() {
+            println!();
+        }
+        let interfaces = match &stdin_names {
+            Some(names) => Box::new(names.clone().into_iter()),
+            None => interface_names(&args).await,
+        };
+        show_each(socket, family_id, interfaces, &args, Some(&mut state)).await;
+        async_io::Timer::after(interval + jitter(args.jitter)).await;
+    }
+}
+
+async fn interface_names(args: &ShowArgs) -> Box<dyn Iterator<Item = OsString>> {
+    let interface = args.interface.as_ref().or(args.interface_flag.as_ref());
+
+    match interface {
+        None => {
+            let links = async { rt_wireguard_links(&mut rt_socket()?).await }.await;
+            match links {
+                Ok(links) => Box::new(links.into_iter().map(OsString::from)),
+                Err(err) => fail("failed to list links", err),
+            }
+        }
+        Some(interface) if interface == "-" => Box::new(
+            std::io::stdin()
+                .lock()
+                .split(b'\n')
+                .map(|line| line.unwrap_or_else(|err| fail("failed to read stdin", err)))
+                .map(|line| OsString::from_vec(line.trim_ascii().to_owned()))
+                .filter(|line| !line.is_empty()),
+        ),
+        Some(interface) => Box::new(std::iter::once(interface.clone())),
+    }
+}
+
+/// The listed interfaces so far, and with --json for all interfaces, their
+/// JSON to print together at the end.
+#[derive(Default)]
+struct Shown {
+    summary: JsonSummary,
+    json: Option<Vec<serde_json::Value>>,
+}
+
+/// The totals over all listed interfaces.
+#[derive(Default, serde::Serialize)]
+struct JsonSummary {
+    interfaces: usize,
+    peers: usize,
+    rx_bytes: u64,
+    tx_bytes: u64,
+}
+
+impl JsonSummary {
+    fn add_peer(&mut self, peer: &WgPeer) {
+        self.peers += 1;
+        self.rx_bytes = self.rx_bytes.saturating_add(peer.rx_bytes);
+        self.tx_bytes = self.tx_bytes.saturating_add(peer.tx_bytes);
+    }
+}
+
+/// Shows each of `interfaces`, returning the exit status of the last one
+/// that failed, or 0. `watch` is the state kept between --watch refreshes.
+///
+/// Unless a single interface was asked for, the listing ends with a summary
+/// of the interfaces that were listed, and --json prints them all in one
+/// document.
+async fn show_each(
+    socket: &mut Async<Socket>,
+    family_id: u16,
+    interfaces: Box<dyn Iterator<Item = OsString>>,
+    args: &ShowArgs,
+    mut watch: Option<&mut WatchState>,
+) -> i32 {
+    let interface = args.interface.as_ref().or(args.interface_flag.as_ref());
+    let all = interface.is_none_or(|interface| interface == "-");
+    let mut shown = Shown {
+        json: (all && (args.json || args.msgpack)).then(Vec::new),
+        ..Default::default()
+    };
+
+    let mut status = 0;
+    for (i, interface) in interfaces.enumerate() {
+        // JSON output is one line per interface, so it needs no separator.
+        if i != 0 && !args.machine_readable() {
+            println!();
+        }
+        let watch = watch.as_deref_mut();
+        let result = show_device(socket, family_id, &interface, args, watch, &mut shown).await;
+        let Err(err) = result else {
+            continue;
+        };
+        let err = Error::from(err);
+        if args.missing_ok && matches!(err, Error::NoDevice) {
+            let missing = serde_json::json!({
+                "interface": interface.to_string_lossy(),
+                "present": false,
+            });
+            match &mut shown.json {
+                Some(all) => all.push(missing),
+                None => print_document(&missing, args.msgpack)
+                    .unwrap_or_else(|err| fail("failed to write", err)),
+            }
+            continue;
+        }
+        print_error(interface.display(), &err);
+        status = err.exit_code();
+    }
+
+    if let Some(interfaces) = shown.json {
+        #[derive(serde::Serialize)]
+        struct JsonAll {
+            interfaces: Vec<serde_json::Value>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            summary: Option<JsonSummary>,
+        }
+
+        let all = JsonAll {
+            interfaces,
+            summary: (!args.no_summary).then_some(shown.summary),
+        };
+        let all = serde_json::to_value(&all)
+            .unwrap_or_else(|err| fail("failed to format JSON", Error::Other(err.to_string())));
+        print_document(&all, args.msgpack).unwrap_or_else(|err| fail("failed to write", err));
+    } else if all && !args.no_summary && !args.machine_readable() && shown.summary.interfaces != 0 {
+        let JsonSummary {
+            interfaces,
+            peers,
+            rx_bytes,
+            tx_bytes,
+        } = shown.summary;
+        let (rx, tx) = (wg_bytes(rx_bytes), wg_bytes(tx_bytes));
+        println!("\nSummary: {interfaces} interfaces, {peers} peers, {rx} received, {tx} sent");
+    }
+    status
+}
+
+async fn show_device(
+    socket: &mut Async<Socket>,
+    family_id: u16,
+    interface: &OsStr,
+    args: &ShowArgs,
+    mut watch: Option<&mut WatchState>,
+    shown: &mut Shown,
+) -> std::io::Result<()> {
+    let ShowArgs {
+        interface: _,
+        interface_flag: _,
+        show_private_key_presence,
+        assert_peers_min,
+        ref assert_endpoint,
+        verbose,
+        with_link_info,
+        strict,
+        export,
+        json,
+        ndjson,
+        msgpack,
+        missing_ok: _,
+        debug_attrs,
+        show_zero,
+        anonymize,
+        iso8601_duration,
+        count_addresses,
+        group_by_endpoint,
+        bucket_handshakes,
+        show_flags,
+        sort,
+        compat,
+        only_with_endpoint,
+        ref labels,
+        routes_script,
+        no_peers,
+        total_transfer,
+        endpoint_family_summary,
+        no_summary: _,
+        fail_on_stale,
+        active_within,
+        watch: _,
+        no_clear: _,
+        jitter: _,
+        baseline_after: _,
+        history: _,
+    } = *args;
+
+    let mut endpoint_mismatches = 0;
+    if !assert_endpoint.is_empty() {
+        let device = wg_device(socket, family_id, interface).await?;
+        for (key, expected) in assert_endpoint {
+            let Some(peer) = device.peer(key) else {
+                eprintln!("{}: no peer {}", interface.display(), wg_public_key(key));
+                endpoint_mismatches += 1;
+                continue;
+            };
+            if peer.endpoint != wg_endpoint(*expected) {
+                let actual = peer
+                    .endpoint
+                    .map_or("(none)".to_owned(), |ep| ep.to_string());
+                eprintln!(
+                    "{}: peer {} has endpoint {actual}, expected {expected}",
+                    interface.display(),
+                    wg_public_key(key)
+                );
+                endpoint_mismatches += 1;
+            }
+        }
+
+        if !verbose {
+            return match endpoint_mismatches {
+                0 => Ok(()),
+                n => Err(std::io::Error::other(format!(
+                    "{n} endpoint assertions failed"
+                ))),
+            };
+        }
+    }
+
+    if let Some(min) = assert_peers_min {
+        let peers = wg_device(socket, family_id, interface).await?.peers.len();
+
+        if peers < min {
+            return Err(std::io::Error::other(format!(
+                "{peers} peers configured, expected at least {min}"
+            )));
+        }
+        return Ok(());
+    }
+
+    if debug_attrs {
+        print_debug_attrs(
+            &wg_get_device(socket, family_id, interface).await?,
+            anonymize,
+        );
+        return Ok(());
+    }
+
+    if let Some(export) = export {
+        let mut peers = wg_device(socket, family_id, interface).await?.peers;
+        if only_with_endpoint {
+            peers.retain(|peer| peer.endpoint.is_some());
+        }
+        sort_peers(&mut peers, sort);
+        export_peers(export, &peers, anonymize);
+        return check_stale(&peers, SystemTime::now(), args);
+    }
+
+    if json || ndjson || msgpack {
+        let mut device = wg_device(socket, family_id, interface).await?;
+        if only_with_endpoint {
+            device.peers.retain(|peer| peer.endpoint.is_some());
+        }
+        sort_peers(&mut device.peers, sort);
+
+        let labels = match labels {
+            Some(path) => read_key_file(path)?.into_iter().collect(),
+            None => HashMap::new(),
+        };
+        shown.summary.interfaces += 1;
+        device
+            .peers
+            .iter()
+            .for_each(|peer| shown.summary.add_peer(peer));
+        let now = SystemTime::now();
+        let json = JsonDevice::new(&device, &labels, now, args);
+        let json = serde_json::to_value(&json).map_err(std::io::Error::other)?;
+        match &mut shown.json {
+            Some(all) => all.push(json),
+            None if ndjson => ndjson_lines(json)
+                .iter()
+                .for_each(|line| println!("{line}")),
+            None => print_document(&json, msgpack)?,
+        }
+        return check_stale(&device.peers, now, args);
+    }
+
+    if routes_script {
+        print_routes_script(&wg_device(socket, family_id, interface).await?, interface);
+        return Ok(());
+    }
+
+    if let Some(Compat::Wg) = compat {
+        let device = wg_device(socket, family_id, interface).await?;
+        let now = SystemTime::now();
+        write_wg_compat(&mut std::io::stdout().lock(), &device, now, anonymize)?;
+        return check_stale(&device.peers, now, args);
+    }
+
+    if group_by_endpoint {
+        let peers = wg_device(socket, family_id, interface).await?.peers;
+        print_endpoint_groups(&peers, anonymize);
+        return check_stale(&peers, SystemTime::now(), args);
+    }
+
+    if bucket_handshakes {
+        let peers = wg_device(socket, family_id, interface).await?.peers;
+        let now = SystemTime::now();
+        print_handshake_buckets(&peers, now, anonymize);
+        return check_stale(&peers, now, args);
+    }
+
+    let labels = match labels {
+        Some(path) => read_key_file(path)?.into_iter().collect(),
+        None => HashMap::new(),
+    };
+
+    // All handshake ages are relative to the same instant.
+    let now = SystemTime::now();
+
+    let mut stale_peers = 0;
+
+    let mut print_peer = |peer: WgPeer| {
+        if only_with_endpoint && peer.endpoint.is_none() {
+            return;
+        }
+        shown.summary.add_peer(&peer);
+        if no_peers {
+            return;
+        }
+        let stale = fail_on_stale && is_stale(&peer, now, active_within);
+        let notes = watch
+            .as_deref_mut()
+            .map(|watch| watch.sample(interface, &peer, now))
+            .unwrap_or_default();
+        let label = labels.get(&wg_public_key(&peer.public_key));
+        let label = label.map_or(String::new(), |label| format!(" {label}"));
+        if stale {
+            stale_peers += 1;
+        }
+        let marks: Vec<_> = [(stale, "stale"), (notes.stalled, "stalled")]
+            .into_iter()
+            .filter_map(|(marked, mark)| marked.then_some(mark))
+            .collect();
+        if marks.is_empty() {
+            println!("\nPeer:{label}");
+        } else {
+            println!("\nPeer ({}):{label}", marks.join(", "));
+        }
+
+        println!("  Public key: {}", format_key(&peer.public_key, anonymize));
+        if peer.preshared_key.is_some() {
+            println!("  Preshared key: (hidden)");
+        }
+        match peer.last_handshake {
+            Some(ts) => {
+                let age = format_handshake_age(now, ts, iso8601_duration);
+                println!("  Last handshake: {age}")
+            }
+            None if show_zero => println!("  Last handshake: (never)"),
+            None => {}
+        }
+        if let Some(sparkline) = &notes.sparkline {
+            println!("  Handshakes: {sparkline}");
+        }
+        // Like wg(8), only once anything was sent or received.
+        if peer.rx_bytes != 0 || peer.tx_bytes != 0 {
+            let (rx, tx) = (wg_bytes(peer.rx_bytes), wg_bytes(peer.tx_bytes));
+            println!("  Transfer: {rx} received, {tx} sent");
+        }
+        if peer.persistent_keepalive != 0 {
+            println!("  KeepAlive: {}", peer.persistent_keepalive);
+        }
+        if let Some(endpoint) = peer.endpoint {
+            println!("  Endpoint: {}", format_endpoint(&endpoint, anonymize));
+        }
+        if let Some(previous) = notes.roamed_from {
+            let endpoint = |endpoint: Option<_>| {
+                endpoint.map_or("(none)".to_owned(), |ep| format_endpoint(&ep, anonymize))
+            };
+            let (from, to) = (endpoint(previous), endpoint(peer.endpoint));
+            println!("  Endpoint changed: {from} -> {to}");
+        }
+        println!("  Allowed ips: {}", format_allowed_ips(&peer.allowed_ips));
+        if count_addresses {
+            match allowed_addresses(&peer.allowed_ips) {
+                count if count > 1 << 64 => println!("  Allowed addresses: >2^64"),
+                count => println!("  Allowed addresses: {count}"),
+            }
+        }
+        for nla in &peer.other_attrs {
+            unhandled_attr(strict, "peer", nla);
+        }
+    };
+
+    // Summaries go under the interface header, so they need the whole dump
+    // before anything is printed.
+    let messages = if total_transfer || endpoint_family_summary {
+        Some(wg_get_device(socket, family_id, interface).await?)
+    } else {
+        None
+    };
+    let listed: Vec<_> = match &messages {
+        Some(messages) => WgDevice::from_messages(messages.clone())?.peers,
+        None => Vec::new(),
+    };
+    let listed: Vec<_> = listed
+        .into_iter()
+        .filter(|peer| !only_with_endpoint || peer.endpoint.is_some())
+        .collect();
+    let total = transfer_totals(&listed);
+    let families = endpoint_families(&listed);
+
+    // Otherwise peers are printed as each reply datagram arrives so a large
+    // dump is never held in memory, unless they have to be sorted first.
+    // Peers with many allowed ips are split across messages, so they are
+    // joined up before printing or sorting.
+    let mut sorted = WgDevice::default();
+    let mut stream = WgPeerStream::default();
+    let mut first = true;
+    let mut failed = None;
+
+    // The reply is handled as it arrives, so the link is looked up first.
+    let link = if with_link_info {
+        wg_link(interface).await
+    } else {
+        None
+    };
+
+    let mut print_message = |msg: GenlMessage<Wireguard>| {
+        let device = match WgDevice::try_from(msg) {
+            Ok(device) => device,
+            Err(err) => {
+                failed.get_or_insert(err);
+                return;
+            }
+        };
+
+        // The device attributes all come in the first message.
+        if std::mem::take(&mut first) {
+            if let Some(name) = &device.name {
+                println!("Interface: {name}");
+                if let Some(link) = &link {
+                    let mtu = link.attributes.iter().find_map(|attr| match attr {
+                        LinkAttribute::Mtu(mtu) => Some(mtu),
+                        _ => None,
+                    });
+                    if let Some(mtu) = mtu {
+                        println!("MTU: {mtu}");
+                    }
+                    println!("State: {}", link_state(link));
+                }
+                if total_transfer {
+                    let (rx, tx) = (wg_bytes(total.0), wg_bytes(total.1));
+                    println!("Total transfer: {rx} received, {tx} sent");
+                }
+                if endpoint_family_summary {
+                    let (v4, v6, none) = families;
+                    println!("Endpoints: {v4} IPv4, {v6} IPv6, {none} none");
+                }
+            }
+            if let Some(key) = device.private_key {
+                if show_private_key_presence {
+                    println!("Private key: (hidden)");
+                }
+                // Older kernels may not report the public key, so derive it.
+                if device.public_key.is_none() {
+                    let key = format_key(&x25519_public(&key), anonymize);
+                    println!("Public key (derived): {key}");
+                }
+            }
+            if let Some(key) = device.public_key {
+                println!("Public key: {}", format_key(&key, anonymize));
+            }
+            println!("Listen port: {}", device.listen_port);
+            if device.fwmark != 0 {
+                println!("FwMark: {}", device.fwmark);
+            }
+            if let Some(flags) = device.flags.filter(|_| show_flags) {
+                println!("Flags: {}", device_flag_names(flags).join(", "));
+            }
+        }
+        for nla in &device.other_attrs {
+            unhandled_attr(strict, "device", nla);
+        }
+
+        // With --no-peers, the peers are only counted for the summary.
+        if sort.is_some() && !no_peers {
+            sorted.merge(device);
+        } else {
+            device
+                .peers
+                .into_iter()
+                .filter_map(|peer| stream.push(peer))
+                .for_each(&mut print_peer);
+        }
+    };
+
+    match messages {
+        Some(messages) => messages.into_iter().for_each(&mut print_message),
+        None => wg_get_device_each(socket, family_id, interface, &mut print_message).await?,
+    }
+    if let Some(err) = failed {
+        return Err(err);
+    }
+
+    stream.finish().into_iter().for_each(&mut print_peer);
+    sort_peers(&mut sorted.peers, sort);
+    sorted.peers.into_iter().for_each(&mut print_peer);
+    shown.summary.interfaces += 1;
+
+    if stale_peers != 0 {
+        return Err(std::io::Error::other(format!("{stale_peers} stale peers")));
+    }
+    if endpoint_mismatches != 0 {
+        return Err(std::io::Error::other(format!(
+            "{endpoint_mismatches} endpoint assertions failed"
+        )));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn iso8601_duration() {
+        assert_eq!(format_iso8601_duration(0), "PT0S");
+        assert_eq!(format_iso8601_duration(42), "PT42S");
+        assert_eq!(format_iso8601_duration(60), "PT1M");
+        assert_eq!(format_iso8601_duration(133), "PT2M13S");
+        assert_eq!(format_iso8601_duration(3600), "PT1H");
+        assert_eq!(format_iso8601_duration(3733), "PT1H2M13S");
+    }
+
+    #[test]
+    fn handshake_age_iso8601_is_bare() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let ts = now - Duration::from_secs(133);
+        assert_eq!(format_handshake_age(now, ts, true), "PT2M13S");
+        assert_eq!(format_handshake_age(now, ts, false), "133s ago");
+    }
+
+    #[test]
+    fn future_handshake_is_just_now() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let ts = now + Duration::from_secs(30);
+        assert_eq!(format_handshake_age(now, ts, false), "just now");
+        assert_eq!(format_handshake_age(now, ts, true), "PT0S");
+    }
+
+    fn hex_key(hex: &str) -> [u8; 32] {
+        let bytes: Vec<u8> = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect();
+        bytes.try_into().unwrap()
+    }
+
+    // RFC 7748, section 6.1.
+    #[test]
+    fn x25519_public_rfc7748_alice() {
+        let private = hex_key("77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a");
+        let public = hex_key("8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a");
+        assert_eq!(x25519_public(&private), public);
+    }
+
+    #[test]
+    fn x25519_public_rfc7748_bob() {
+        let private = hex_key("5dab087e624a8a4b79e17f8b83800ee66f3bb1292618b6fd1c2f8b27ff88e0eb");
+        let public = hex_key("de9edb7d7b7dc1b4d35b61c2ece435373f8343c85b78674dadfc7e146f882b4f");
+        assert_eq!(x25519_public(&private), public);
+    }
+
+    #[test]
+    fn x25519_public_clamps_the_scalar() {
+        let unclamped = [0xff; 32];
+        assert_eq!(x25519_public(&unclamped), x25519_public(&clamp(unclamped)));
+    }
+
+    #[test]
+    fn clamping_check() {
+        assert!(is_clamped(&wg_generate_private_key().unwrap()));
+        assert!(!is_clamped(&[0xff; 32]));
+        assert!(!is_clamped(&[0; 32]));
+
+        let mut key = [0x55; 32];
+        assert!(!is_clamped(&key));
+        key = clamp(key);
+        assert!(is_clamped(&key));
+        assert_eq!((key[0], key[1], key[31]), (0x50, 0x55, 0x55));
+        assert_eq!(clamp(key), key);
+    }
+
+    #[test]
+    fn config_sources() {
+        let dir = std::env::temp_dir().join(format!("wg-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("wg0.conf");
+        std::fs::write(&path, "[Interface]\nListenPort = 51820\n").unwrap();
+
+        let read = |source: String| read_config(std::path::Path::new(&source));
+        let text = read(path.display().to_string()).unwrap();
+        assert_eq!(read(format!("file://{}", path.display())).unwrap(), text);
+        assert_eq!(
+            read(format!("file://localhost{}", path.display())).unwrap(),
+            text
+        );
+        assert!(matches!(
+            read("file://host/wg0.conf".to_owned()),
+            Err(Error::Invalid(_))
+        ));
+        assert!(matches!(
+            read("https://example.com/wg0.conf".to_owned()),
+            Err(Error::Invalid(err)) if err.starts_with("https://")
+        ));
+        assert!(matches!(
+            read(dir.join("missing.conf").display().to_string()),
+            Err(Error::Io(_))
+        ));
+
+        let big = dir.join("big.conf");
+        std::fs::write(&big, vec![b'#'; CONFIG_SIZE_LIMIT as usize + 1]).unwrap();
+        assert!(matches!(
+            read(big.display().to_string()),
+            Err(Error::Invalid(_))
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn batch_file_lines() {
+        let text = "# keys\nset wg0 --listen-port 51820\n\n  \nset wg1 --fwmark off\n";
+        let lines: Vec<_> = batch_lines(text).collect();
+        assert_eq!(
+            lines,
+            [
+                (2, "set wg0 --listen-port 51820"),
+                (5, "set wg1 --fwmark off")
+            ]
+        );
+
+        let set = parse_batch_line("set wg0 --listen-port 51820").unwrap();
+        assert_eq!(
+            (set.interface.to_str(), set.listen_port),
+            (Some("wg0"), Some(51820))
+        );
+        let set = parse_batch_line("set wg0  peer AAAA --persistent-keepalive 25").unwrap();
+        assert!(matches!(
+            set.peer,
+            Some(SetPeer::Peer { public_key, persistent_keepalive: Some(25), .. }) if public_key == "AAAA"
+        ));
+
+        let err = |line| match parse_batch_line(line) {
+            Err(Error::Invalid(err)) => err,
+            _ => panic!("{line:?} parsed"),
+        };
+        assert_eq!(err("set wg0"), "set needs something to change");
+        assert!(
+            err("set wg0 --bogus").contains("'--bogus'"),
+            "{}",
+            err("set wg0 --bogus")
+        );
+        assert!(err("setconf wg0 wg0.conf").contains("'setconf'"));
+        assert!(err("set wg0 --listen-port x").contains("'x'"));
+    }
+
+    #[test]
+    fn default_route_shorthands() {
+        let expand = |list: &str| {
+            let args: Vec<_> = list
+                .split(',')
+                .map(|arg| parse_allowed_ip(arg).unwrap())
+                .collect();
+            expand_allowed_ips(&args).map(|ips| format_allowed_ips(&ips))
+        };
+        assert_eq!(expand("default").unwrap(), "0.0.0.0/0, ::/0");
+        assert_eq!(expand("default4").unwrap(), "0.0.0.0/0");
+        assert_eq!(expand("default6").unwrap(), "::/0");
+        assert_eq!(expand("default6,default").unwrap(), "::/0, 0.0.0.0/0");
+        assert_eq!(
+            expand("0.0.0.0/0,10.0.0.1").unwrap(),
+            "0.0.0.0/0, 10.0.0.1/32"
+        );
+        assert!(expand("default,10.0.0.0/8").is_err());
+        assert!(expand("fd00::/8,default6").is_err());
+        assert!(parse_allowed_ip("defaults").is_err());
+    }
+
+    #[test]
+    fn network_address_clears_host_bits() {
+        let network = |cidr| {
+            let (ip, prefix) = parse_cidr(cidr).unwrap();
+            format!("{}/{prefix}", network_address(ip, prefix))
+        };
+        assert_eq!(network("10.0.0.5/24"), "10.0.0.0/24");
+        assert_eq!(network("10.0.0.5/31"), "10.0.0.4/31");
+        assert_eq!(network("10.0.0.5/32"), "10.0.0.5/32");
+        assert_eq!(network("10.1.2.3/8"), "10.0.0.0/8");
+        assert_eq!(network("10.1.2.3/0"), "0.0.0.0/0");
+        assert_eq!(network("fd00::1:2/64"), "fd00::/64");
+        assert_eq!(network("fd00::3/127"), "fd00::2/127");
+        assert_eq!(network("fd00::3/128"), "fd00::3/128");
+        assert_eq!(network("fd12:3456::1/20"), "fd12:3000::/20");
+        assert_eq!(network("fd00::1/0"), "::/0");
+    }
+
+    fn show_args(args: &[&str]) -> ShowArgs {
+        let args = std::iter::once("wg-test").chain(args.iter().copied());
+        <Args as clap::Parser>::try_parse_from(args).unwrap().show
+    }
+
+    #[test]
+    fn json_peers() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let device = compat_device(now);
+        let json = JsonDevice::new(&device, &HashMap::new(), now, &show_args(&["--json"]));
+        let json = serde_json::to_value(json).unwrap();
+
+        let counts: Vec<_> = json["peers"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|peer| peer["allowed_ips_count"].as_u64().unwrap())
+            .collect();
+        assert_eq!(counts, [0, 1, 2]);
+        assert_eq!(json["peers"][2]["allowed_ips"][1], "10.0.1.0/24");
+        assert!(json["peers"][2].get("stale").is_none());
+
+        let args = show_args(&["--json", "--fail-on-stale", "--active-within", "2m"]);
+        let json = serde_json::to_value(JsonDevice::new(&device, &HashMap::new(), now, &args));
+        let stale: Vec<_> = json.unwrap()["peers"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|peer| peer["stale"].as_bool().unwrap())
+            .collect();
+        assert_eq!(stale, [true, true, false]);
+        assert!(check_stale(&device.peers, now, &args).is_err());
+
+        let args = show_args(&["--fail-on-stale", "--active-within", "2h"]);
+        assert!(check_stale(&device.peers[1..], now, &args).is_ok());
+        assert!(check_stale(&device.peers, now, &show_args(&[])).is_ok());
+    }
+
+    #[test]
+    fn ndjson_splits_out_peers() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let device = compat_device(now);
+        let json = JsonDevice::new(&device, &HashMap::new(), now, &show_args(&["--ndjson"]));
+        let lines = ndjson_lines(serde_json::to_value(json).unwrap());
+        assert_eq!(lines.len(), 4);
+
+        let lines: Vec<serde_json::Value> = lines
+            .iter()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines[0]["interface"], "wg0");
+        assert_eq!(lines[0]["listen_port"], 51820);
+        assert!(lines[0].get("peers").is_none());
+        for (peer, key) in lines[1..].iter().zip([[4; 32], [3; 32], [2; 32]]) {
+            assert_eq!(peer["interface"], "wg0");
+            assert_eq!(peer["public_key"], wg_public_key(&key));
+        }
+        assert_eq!(lines[3]["persistent_keepalive"], 25);
+    }
+
+    #[test]
+    fn msgpack_encoding() {
+        use serde_json::json;
+
+        let encode = |value: serde_json::Value| {
+            let mut out = Vec::new();
+            encode_msgpack(&value, &mut out);
+            out
+        };
+        assert_eq!(encode(json!(null)), [0xc0]);
+        assert_eq!(encode(json!([true, false])), [0x92, 0xc3, 0xc2]);
+        assert_eq!(encode(json!(0x7f)), [0x7f]);
+        assert_eq!(encode(json!(0x80)), [0xcc, 0x80]);
+        assert_eq!(encode(json!(51820)), [0xcd, 0xca, 0x6c]);
+        assert_eq!(encode(json!(0x1_0000)), [0xce, 0, 1, 0, 0]);
+        assert_eq!(encode(json!(1u64 << 32)), [0xcf, 0, 0, 0, 1, 0, 0, 0, 0]);
+        assert_eq!(encode(json!(-1)), [0xff]);
+        assert_eq!(encode(json!(-33)), [0xd0, 0xdf]);
+        assert_eq!(encode(json!(-0x81)), [0xd1, 0xff, 0x7f]);
+        assert_eq!(encode(json!(-0x8001)), [0xd2, 0xff, 0xff, 0x7f, 0xff]);
+        assert_eq!(encode(json!(i64::MIN))[0], 0xd3);
+        assert_eq!(encode(json!(0.5)), [0xcb, 0x3f, 0xe0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(encode(json!({"a": "wg0"})), b"\x81\xa1a\xa3wg0");
+
+        let long = "k".repeat(32);
+        assert_eq!(encode(json!(long))[..2], [0xd9, 32]);
+        assert_eq!(encode(json!("k".repeat(256)))[..3], [0xda, 1, 0]);
+        assert_eq!(encode(json!(vec![0; 16]))[..3], [0xdc, 0, 16]);
+        let map: serde_json::Map<_, _> = (0..16).map(|i| (i.to_string(), json!(i))).collect();
+        assert_eq!(encode(json!(map))[..3], [0xde, 0, 16]);
+    }
+
+    #[test]
+    fn json_present_with_missing_ok() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let device = compat_device(now);
+        let json = |args| {
+            let json = JsonDevice::new(&device, &HashMap::new(), now, &show_args(args));
+            serde_json::to_value(json).unwrap()
+        };
+        assert_eq!(json(&["--json", "--missing-ok"])["present"], true);
+        assert!(json(&["--json"]).get("present").is_none());
+
+        let parses = |args: &[&str]| {
+            let args = std::iter::once("wg-test").chain(args.iter().copied());
+            <Args as clap::Parser>::try_parse_from(args).is_ok()
+        };
+        assert!(parses(&["--ndjson", "--missing-ok"]));
+        assert!(!parses(&["--missing-ok"]));
+    }
+
+    fn compat_device(now: SystemTime) -> WgDevice {
+        let ago = |secs| Some(now - Duration::from_secs(secs));
+        WgDevice {
+            name: Some("wg0".to_owned()),
+            private_key: Some([1; 32]),
+            public_key: Some([5; 32]),
+            listen_port: 51820,
+            fwmark: 0x1234,
+            peers: vec![
+                WgPeer {
+                    public_key: [4; 32],
+                    ..Default::default()
+                },
+                WgPeer {
+                    public_key: [3; 32],
+                    endpoint: Some("[2001:db8::1]:51820".parse().unwrap()),
+                    allowed_ips: vec![("fd00::3".parse().unwrap(), 128)],
+                    last_handshake: ago(3725),
+                    rx_bytes: 100,
+                    ..Default::default()
+                },
+                WgPeer {
+                    public_key: [2; 32],
+                    preshared_key: Some([6; 32]),
+                    endpoint: Some("192.0.2.1:51820".parse().unwrap()),
+                    allowed_ips: vec![
+                        ("10.0.0.2".parse().unwrap(), 32),
+                        ("10.0.1.0".parse().unwrap(), 24),
+                    ],
+                    persistent_keepalive: 25,
+                    last_handshake: ago(65),
+                    rx_bytes: 1536,
+                    tx_bytes: 3 << 20,
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn wg_compat_matches_wg_show() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let mut out = Vec::new();
+        write_wg_compat(&mut out, &compat_device(now), now, false).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            include_str!("../tests/golden/wg-show.txt")
+        );
+    }
+
+    #[test]
+    fn wg_compat_anonymize_hides_keys_and_endpoints() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let mut out = Vec::new();
+        write_wg_compat(&mut out, &compat_device(now), now, true).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        for key in [[2; 32], [3; 32], [4; 32], [5; 32]] {
+            assert!(!out.contains(&wg_public_key(&key)));
+            assert!(out.contains(&anonymized_key(&key)));
+        }
+        assert!(!out.contains("192.0.2.1") && !out.contains("2001:db8::1"));
+        assert!(out.contains("  endpoint: *:51820"));
+    }
+
+    fn config_peer(n: u16, ips: u16) -> nlas::WgPeer {
+        let mut key = [0; 32];
+        key[..2].copy_from_slice(&n.to_be_bytes());
+        let ips = (0..ips)
+            .map(|i| wg_allowed_ip((IpAddr::from([10, 0, (i >> 8) as u8, i as u8]), 32)))
+            .collect();
+        nlas::WgPeer(vec![
+            WgPeerAttrs::PublicKey(key),
+            WgPeerAttrs::Flags(WGPEER_F_REPLACE_ALLOWEDIPS),
+            WgPeerAttrs::AllowedIps(ips),
+        ])
+    }
+
+    fn request_peers(request: &[WgDeviceAttrs]) -> &[nlas::WgPeer] {
+        request
+            .iter()
+            .find_map(|nla| match nla {
+                WgDeviceAttrs::Peers(peers) => Some(&peers[..]),
+                _ => None,
+            })
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn split_set_device_spreads_many_peers() {
+        let nlas = vec![
+            WgDeviceAttrs::ListenPort(51820),
+            WgDeviceAttrs::Flags(WGDEVICE_F_REPLACE_PEERS),
+            WgDeviceAttrs::Peers((0..3000).map(|n| config_peer(n, 2)).collect()),
+        ];
+        let requests = split_set_device(nlas);
+
+        assert!(requests.len() > 1);
+        for (i, request) in requests.iter().enumerate() {
+            let device_attrs = request
+                .iter()
+                .filter(|nla| !matches!(nla, WgDeviceAttrs::Peers(_)))
+                .count();
+            assert_eq!(device_attrs, if i == 0 { 2 } else { 0 });
+            for nla in request {
+                assert!(nla.buffer_len() <= u16::MAX as usize);
+            }
+        }
+        let keys: Vec<_> = requests
+            .iter()
+            .flat_map(|request| request_peers(request))
+            .map(|peer| peer[0].clone())
+            .collect();
+        let expected: Vec<_> = (0..3000).map(|n| config_peer(n, 0).0[0].clone()).collect();
+        assert_eq!(keys, expected);
+    }
+
+    #[test]
+    fn split_set_device_continues_long_allowed_ips() {
+        let peer = config_peer(1, 5000);
+        let requests = split_set_device(vec![WgDeviceAttrs::Peers(vec![peer.clone()])]);
+        assert!(requests.len() > 1);
+
+        let entries: Vec<_> = requests
+            .iter()
+            .flat_map(|request| request_peers(request))
+            .collect();
+        assert_eq!(entries[0][..2], peer[..2]);
+        for entry in &entries[1..] {
+            assert_eq!(entry.len(), 2);
+            assert_eq!(entry[0], peer[0]);
+        }
+        let ips: Vec<_> = entries
+            .iter()
+            .flat_map(|entry| match entry.last() {
+                Some(WgPeerAttrs::AllowedIps(ips)) => wg_test::wg_allowed_ips(ips),
+                _ => Vec::new(),
+            })
+            .collect();
+        let WgPeerAttrs::AllowedIps(expected) = &peer[2] else {
+            unreachable!()
+        };
+        assert_eq!(ips, wg_test::wg_allowed_ips(expected));
+        for request in &requests {
+            for nla in request {
+                assert!(nla.buffer_len() <= u16::MAX as usize);
+            }
+        }
+    }
+
+    #[test]
+    fn errno_maps_to_status_and_code() {
+        let err = |errno| Error::from(std::io::Error::from_raw_os_error(errno));
+        assert_eq!(err(libc::ENODEV).exit_code(), 2);
+        assert_eq!(err(libc::ENODEV).code(), "ENODEV");
+        assert_eq!(err(libc::EPERM).exit_code(), 3);
+        assert_eq!(err(libc::EPERM).code(), "EPERM");
+        assert_eq!(err(libc::EINVAL).exit_code(), 1);
+        assert_eq!(err(libc::EINVAL).code(), "EOTHER");
+    }
+
+    #[test]
+    fn pick_endpoint_from_mixed_families() {
+        let addrs: Vec<std::net::SocketAddr> = [
+            "[2001:db8::1]:51820",
+            "[::ffff:192.0.2.1]:51820",
+            "192.0.2.2:51820",
+        ]
+        .iter()
+        .map(|addr| addr.parse().unwrap())
+        .collect();
+        let pick = |addrs: &[_], family| {
+            pick_endpoint("vpn.example.com:51820", addrs.to_vec(), family)
+                .map(|addr| addr.to_string())
+        };
+
+        assert_eq!(pick(&addrs, None).unwrap(), "[2001:db8::1]:51820");
+        assert_eq!(
+            pick(&addrs, Some(Family::Ipv6)).unwrap(),
+            "[2001:db8::1]:51820"
+        );
+        assert_eq!(pick(&addrs, Some(Family::Ipv4)).unwrap(), "192.0.2.1:51820");
+        assert_eq!(pick(&addrs[1..], None).unwrap(), "192.0.2.1:51820");
+        assert_eq!(
+            pick(&addrs[1..], Some(Family::Ipv6)),
+            Err("vpn.example.com:51820: no IPv6 addresses".to_owned())
+        );
+        assert_eq!(
+            pick(&addrs[..1], Some(Family::Ipv4)),
+            Err("vpn.example.com:51820: no IPv4 addresses".to_owned())
+        );
+        assert!(pick(&[], None).is_err());
+    }
+
+    #[test]
+    fn parse_duration_accepts() {
+        let secs = |s| parse_duration(s).map(|d| d.as_secs());
+        assert_eq!(secs("30s"), Ok(30));
+        assert_eq!(secs("5m"), Ok(5 * 60));
+        assert_eq!(secs("2h"), Ok(2 * 60 * 60));
+        assert_eq!(secs("1h30m"), Ok(90 * 60));
+        assert_eq!(secs("45"), Ok(45));
+    }
+
+    #[test]
+    fn watch_notes_endpoint_changes() {
+        let mut state = WatchState::default();
+        let mut peer = WgPeer {
+            public_key: [1; 32],
+            endpoint: Some("192.0.2.1:51820".parse().unwrap()),
+            ..Default::default()
+        };
+        let wg0 = OsStr::new("wg0");
+        assert_eq!(state.sample(wg0, &peer, UNIX_EPOCH).roamed_from, None);
+        assert_eq!(state.sample(wg0, &peer, UNIX_EPOCH).roamed_from, None);
+
+        let before = peer.endpoint;
+        peer.endpoint = Some("198.51.100.7:4500".parse().unwrap());
+        assert_eq!(
+            state.sample(wg0, &peer, UNIX_EPOCH).roamed_from,
+            Some(before)
+        );
+        assert_eq!(state.sample(wg0, &peer, UNIX_EPOCH).roamed_from, None);
+        // The same key on another interface is another peer.
+        assert_eq!(
+            state
+                .sample(OsStr::new("wg1"), &peer, UNIX_EPOCH)
+                .roamed_from,
+            None
+        );
+
+        let before = peer.endpoint;
+        peer.endpoint = None;
+        assert_eq!(
+            state.sample(wg0, &peer, UNIX_EPOCH).roamed_from,
+            Some(before)
+        );
+    }
+
+    #[test]
+    fn watch_marks_stalled_peers() {
+        let mut state = WatchState::default();
+        let mut peer = WgPeer {
+            public_key: [1; 32],
+            rx_bytes: 100,
+            ..Default::default()
+        };
+        let wg0 = OsStr::new("wg0");
+        assert!(!state.sample(wg0, &peer, UNIX_EPOCH).stalled);
+
+        // Before the baseline nothing is stalled.
+        assert!(!state.sample(wg0, &peer, UNIX_EPOCH).stalled);
+        state.baseline_due = true;
+        assert!(!state.sample(wg0, &peer, UNIX_EPOCH).stalled);
+        assert!(state.sample(wg0, &peer, UNIX_EPOCH).stalled);
+
+        // Peers first seen later get their own baseline.
+        let other = WgPeer {
+            public_key: [2; 32],
+            ..Default::default()
+        };
+        assert!(!state.sample(wg0, &other, UNIX_EPOCH).stalled);
+        assert!(state.sample(wg0, &other, UNIX_EPOCH).stalled);
+
+        peer.tx_bytes = 1;
+        assert!(!state.sample(wg0, &peer, UNIX_EPOCH).stalled);
+        // The baseline stays where it was taken.
+        peer.tx_bytes = 0;
+        assert!(state.sample(wg0, &peer, UNIX_EPOCH).stalled);
+        peer.last_handshake = Some(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+        assert!(!state.sample(wg0, &peer, UNIX_EPOCH).stalled);
+    }
+
+    #[test]
+    fn watch_keeps_handshake_history() {
+        let mut state = WatchState {
+            history: 3,
+            active_within: Duration::from_secs(80),
+            ..Default::default()
+        };
+        let start = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let mut peer = WgPeer {
+            public_key: [1; 32],
+            ..Default::default()
+        };
+        let wg0 = OsStr::new("wg0");
+        let mut sample = |peer: &WgPeer, secs| {
+            let now = start + Duration::from_secs(secs);
+            state.sample(wg0, peer, now).sparkline.unwrap()
+        };
+        assert_eq!(sample(&peer, 0), " ");
+        peer.last_handshake = Some(start);
+        assert_eq!(sample(&peer, 0), " █");
+        assert_eq!(sample(&peer, 40), " █▄");
+        assert_eq!(sample(&peer, 79), "█▄▁");
+        assert_eq!(sample(&peer, 1000), "▄▁▁");
+    }
+
+    #[test]
+    fn jitter_stays_in_bounds() {
+        assert_eq!(jitter(0), Duration::ZERO);
+        for _ in 0..100 {
+            assert!(jitter(5) <= Duration::from_millis(5));
+        }
+        assert!(jitter(u64::MAX) <= Duration::from_millis(u64::MAX));
+    }
+
+    #[test]
+    fn rfc3339_timestamps() {
+        let at = |secs| format_rfc3339(UNIX_EPOCH + Duration::from_secs(secs));
+        assert_eq!(at(0), "1970-01-01T00:00:00Z");
+        assert_eq!(at(951_782_400), "2000-02-29T00:00:00Z");
+        assert_eq!(at(1_700_000_000), "2023-11-14T22:13:20Z");
+        assert_eq!(at(4_102_444_799), "2099-12-31T23:59:59Z");
+        assert_eq!(at(4_107_542_400), "2100-03-01T00:00:00Z");
+    }
+
+    #[test]
+    fn parse_duration_rejects() {
+        for duration in ["", "5x", "m", "30s5"] {
+            assert!(parse_duration(duration).is_err(), "{duration:?}");
+        }
+    }
 }